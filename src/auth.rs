@@ -0,0 +1,289 @@
+use crate::hex;
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    sys::{bootloader_random_disable, bootloader_random_enable, esp_fill_random, esp_rom_printf},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::CString,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+const NVS_NAMESPACE: &str = "auth";
+const NVS_KEY_TOKENS: &str = "tokens";
+const NVS_TOKENS_MAX_SIZE: usize = 2048;
+const TOKENS_MAX_COUNT: usize = 12;
+const TOKEN_LENGTH: usize = 16;
+const DIGEST_LENGTH: usize = 32;
+// Hex characters of the digest identifying a token
+const TOKEN_ID_LENGTH: usize = 8;
+/// Token created on first boot instead of a random one, e.g. to provision a fleet.
+const BOOTSTRAP_TOKEN: Option<&str> = option_env!("API_TOKEN");
+
+type TokenDigest = [u8; DIGEST_LENGTH];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Status, metrics, diagnostics and update progress
+    Read,
+    /// Text and clock shown on the panel
+    Content,
+    /// Log levels, panel clock and API tokens
+    Config,
+    /// Firmware updates and boot slots
+    Firmware,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [Scope::Read, Scope::Content, Scope::Config, Scope::Firmware];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Content => "content",
+            Scope::Config => "config",
+            Scope::Firmware => "firmware",
+        }
+    }
+
+    fn all() -> Vec<Scope> {
+        Self::ALL.to_vec()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Missing or invalid bearer token")]
+    Unauthenticated,
+    #[error("Token lacks the \"{}\" scope", .0.name())]
+    MissingScope(Scope),
+}
+
+#[derive(Error, Debug)]
+pub enum RevokeError {
+    #[error("No token with id \"{0}\"")]
+    NotFound(String),
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("The last token with every scope can not be revoked")]
+    LastFullAccess,
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredToken {
+    /// Hex encoded SHA-256 of the token, the token itself is never stored
+    digest: String,
+    // Tokens created before scopes existed keep full access
+    #[serde(default = "Scope::all")]
+    scopes: Vec<Scope>,
+}
+
+/// Public view of a stored token.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TokenInfo {
+    pub id: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    digest: TokenDigest,
+    scopes: Vec<Scope>,
+}
+
+/// Scoped bearer tokens accepted by the HTTP server.
+#[derive(Clone)]
+pub struct Auth {
+    tokens: Arc<Mutex<Vec<Token>>>,
+    partition: EspDefaultNvsPartition,
+}
+
+impl Auth {
+    /// Loads the tokens from NVS. Creates the first token with all scopes if there is none yet.
+    pub fn init(partition: EspDefaultNvsPartition) -> Result<Self> {
+        let mut nvs = EspNvs::<NvsDefault>::new(partition.clone(), NVS_NAMESPACE, true)?;
+        let mut buffer = [0u8; NVS_TOKENS_MAX_SIZE];
+        let stored = match nvs.get_str(NVS_KEY_TOKENS, &mut buffer)? {
+            Some(tokens) => serde_json::from_str::<Vec<StoredToken>>(tokens)?,
+            None => {
+                let stored = vec![bootstrap()];
+                nvs.set_str(NVS_KEY_TOKENS, &serde_json::to_string(&stored)?)?;
+                stored
+            }
+        };
+
+        let tokens = stored
+            .into_iter()
+            .filter_map(|token| {
+                let Some(digest) = hex::decode::<DIGEST_LENGTH>(&token.digest) else {
+                    log::warn!("Ignoring invalid stored token digest");
+                    return None;
+                };
+                Some(Token {
+                    digest,
+                    scopes: token.scopes,
+                })
+            })
+            .collect::<Vec<_>>();
+        log::info!("Loaded {} API tokens", tokens.len());
+
+        Ok(Self {
+            tokens: Arc::new(Mutex::new(tokens)),
+            partition,
+        })
+    }
+
+    /// Checks that the token exists and grants the scope. Returns the scopes of the token.
+    pub fn authorize(&self, token: Option<&str>, scope: Scope) -> Result<Vec<Scope>, AuthError> {
+        let token = token.ok_or(AuthError::Unauthenticated)?;
+        let digest: TokenDigest = Sha256::digest(token.as_bytes()).into();
+        let tokens = self.tokens.lock().unwrap();
+
+        // Check every token without stopping early to not leak timing information
+        let scopes = tokens.iter().fold(None, |found, stored| {
+            if equals(&stored.digest, &digest) {
+                Some(stored.scopes.clone())
+            } else {
+                found
+            }
+        });
+        match scopes {
+            None => Err(AuthError::Unauthenticated),
+            Some(scopes) if !scopes.contains(&scope) => Err(AuthError::MissingScope(scope)),
+            Some(scopes) => Ok(scopes),
+        }
+    }
+
+    pub fn tokens(&self) -> Vec<TokenInfo> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|token| TokenInfo {
+                id: token_id(&token.digest),
+                scopes: token.scopes.clone(),
+            })
+            .collect()
+    }
+
+    /// Creates and stores a random token. The token is returned once and can not be recovered.
+    pub fn create(&self, scopes: Vec<Scope>) -> Result<(String, TokenInfo)> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.len() >= TOKENS_MAX_COUNT {
+            bail!("At most {TOKENS_MAX_COUNT} tokens can be stored");
+        }
+
+        let token = random_token();
+        let digest: TokenDigest = Sha256::digest(token.as_bytes()).into();
+        tokens.push(Token {
+            digest,
+            scopes: scopes.clone(),
+        });
+        if let Err(err) = self.store(&tokens) {
+            tokens.pop();
+            return Err(err);
+        }
+
+        let id = token_id(&digest);
+        log::info!("Created API token {id}");
+        Ok((token, TokenInfo { id, scopes }))
+    }
+
+    /// Removes the token with the id on behalf of a token with the scopes.
+    /// Tokens can not revoke tokens with more scopes than their own or the last token
+    /// with every scope, which could not be created again without reflashing.
+    pub fn revoke(&self, id: &str, scopes: &[Scope]) -> Result<(), RevokeError> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let Some(index) = tokens
+            .iter()
+            .position(|token| token_id(&token.digest) == id)
+        else {
+            return Err(RevokeError::NotFound(id.to_string()));
+        };
+
+        if let Some(scope) = tokens[index]
+            .scopes
+            .iter()
+            .find(|scope| !scopes.contains(scope))
+        {
+            return Err(AuthError::MissingScope(*scope).into());
+        }
+        let full_access =
+            |token: &Token| Scope::ALL.iter().all(|scope| token.scopes.contains(scope));
+        if full_access(&tokens[index])
+            && tokens.iter().filter(|token| full_access(token)).count() == 1
+        {
+            return Err(RevokeError::LastFullAccess);
+        }
+
+        let token = tokens.remove(index);
+        if let Err(err) = self.store(&tokens) {
+            tokens.insert(index, token);
+            return Err(err.into());
+        }
+        log::info!("Revoked API token {id}");
+        Ok(())
+    }
+
+    fn store(&self, tokens: &[Token]) -> Result<()> {
+        let stored = tokens
+            .iter()
+            .map(|token| StoredToken {
+                digest: hex::encode(&token.digest),
+                scopes: token.scopes.clone(),
+            })
+            .collect::<Vec<_>>();
+        let mut nvs = EspNvs::<NvsDefault>::new(self.partition.clone(), NVS_NAMESPACE, true)?;
+        nvs.set_str(NVS_KEY_TOKENS, &serde_json::to_string(&stored)?)?;
+        Ok(())
+    }
+}
+
+fn bootstrap() -> StoredToken {
+    let token = match BOOTSTRAP_TOKEN {
+        Some(token) => token.to_string(),
+        None => {
+            // Wi-Fi is not started yet, so the entropy source has to be enabled explicitly
+            unsafe { bootloader_random_enable() };
+            let token = random_token();
+            unsafe { bootloader_random_disable() };
+            // Written to the serial console directly since logs might be forwarded to syslog
+            let message =
+                CString::new(format!("Created initial API token: {token}\n")).unwrap_or_default();
+            unsafe { esp_rom_printf(c"%s".as_ptr(), message.as_ptr()) };
+            token
+        }
+    };
+    log::warn!("No API token found, created the initial one");
+
+    StoredToken {
+        digest: hex::encode(&Sha256::digest(token.as_bytes())),
+        scopes: Scope::all(),
+    }
+}
+
+/// Only truly random while Wi-Fi is running or the bootloader entropy source is enabled.
+fn random_token() -> String {
+    let mut bytes = [0u8; TOKEN_LENGTH];
+    unsafe { esp_fill_random(bytes.as_mut_ptr().cast(), bytes.len()) };
+    hex::encode(&bytes)
+}
+
+fn token_id(digest: &TokenDigest) -> String {
+    let mut id = hex::encode(digest);
+    id.truncate(TOKEN_ID_LENGTH);
+    id
+}
+
+fn equals(left: &TokenDigest, right: &TokenDigest) -> bool {
+    left.iter()
+        .zip(right)
+        .fold(0, |difference, (left, right)| difference | (left ^ right))
+        == 0
+}
@@ -0,0 +1,253 @@
+use anyhow::Result;
+use core::{ffi::CStr, fmt, mem::MaybeUninit, ptr::addr_of_mut};
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    sys::{
+        self, esp_reset_reason, heap_caps_get_free_size, heap_caps_get_largest_free_block,
+        heap_caps_get_minimum_free_size, uxTaskGetNumberOfTasks, uxTaskGetSystemState,
+        TaskStatus_t, MALLOC_CAP_32BIT, MALLOC_CAP_DEFAULT, MALLOC_CAP_DMA, MALLOC_CAP_INTERNAL,
+    },
+};
+use serde::Serialize;
+use std::{panic, sync::OnceLock};
+
+const NVS_NAMESPACE: &str = "diagnostics";
+const NVS_KEY_BOOT_COUNT: &str = "boot_count";
+const NVS_KEY_LAST_PANIC: &str = "last_panic";
+const NVS_KEY_RESTART_REASON: &str = "restart_reason";
+const PANIC_MESSAGE_MAX_SIZE: usize = 256;
+// Tasks created while the system state is collected
+const TASK_STATUS_SLACK: usize = 4;
+const HEAP_CAPABILITIES: [(&str, u32); 4] = [
+    ("default", MALLOC_CAP_DEFAULT),
+    ("internal", MALLOC_CAP_INTERNAL),
+    ("dma", MALLOC_CAP_DMA),
+    ("32bit", MALLOC_CAP_32BIT),
+];
+// Marks the RTC memory as holding a panic record instead of garbage from a power-on
+const PANIC_RECORD_MAGIC: u32 = 0x5041_4e43;
+
+static DIAGNOSTICS: OnceLock<Diagnostics> = OnceLock::new();
+
+/// Survives software resets but not power loss, so the panic is moved into NVS on the next boot.
+#[link_section = ".rtc_noinit"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+#[derive(Clone, Copy)]
+struct PanicRecord {
+    magic: u32,
+    length: usize,
+    message: [u8; PANIC_MESSAGE_MAX_SIZE],
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Diagnostics {
+    pub reset_reason: &'static str,
+    pub boot_count: u32,
+    /// Message and location of the last Rust panic, if there ever was one
+    pub last_panic: Option<String>,
+    /// Why the supervisor restarted the chip, if it caused this boot
+    pub restart_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HeapDiagnostics {
+    pub capability: &'static str,
+    pub free_bytes: usize,
+    pub minimum_free_bytes: usize,
+    pub largest_free_block_bytes: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskDiagnostics {
+    pub name: String,
+    pub priority: u32,
+    /// Least amount of stack ever left, in bytes
+    pub stack_high_water_mark_bytes: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryDiagnostics {
+    pub heap: Vec<HeapDiagnostics>,
+    pub tasks: Vec<TaskDiagnostics>,
+}
+
+/// Counts the boot, collects the reset reason and the panic of the previous run
+/// and installs a panic hook recording the next one.
+pub fn init(partition: EspDefaultNvsPartition) -> Result<()> {
+    let mut nvs = EspNvs::<NvsDefault>::new(partition, NVS_NAMESPACE, true)?;
+
+    let boot_count = nvs.get_u32(NVS_KEY_BOOT_COUNT)?.unwrap_or_default() + 1;
+    nvs.set_u32(NVS_KEY_BOOT_COUNT, boot_count)?;
+
+    if let Some(mut message) = take_panic_record() {
+        log::error!("Recovered from panic: {message}");
+        truncate(&mut message);
+        nvs.set_str(NVS_KEY_LAST_PANIC, &message)?;
+    }
+    let last_panic = read_message(&nvs, NVS_KEY_LAST_PANIC);
+    let restart_reason = read_message(&nvs, NVS_KEY_RESTART_REASON);
+    if let Some(reason) = &restart_reason {
+        log::warn!("Restarted by supervisor: {reason}");
+        nvs.remove(NVS_KEY_RESTART_REASON)?;
+    }
+
+    let reset_reason = reset_reason();
+    log::info!("Boot #{boot_count}, reset reason: {reset_reason}");
+
+    let _ = DIAGNOSTICS.set(Diagnostics {
+        reset_reason,
+        boot_count,
+        last_panic,
+        restart_reason,
+    });
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        store_panic_record(info);
+        default_hook(info);
+    }));
+    Ok(())
+}
+
+/// Stores why the chip is about to be restarted so it can be reported after the next boot.
+pub fn store_restart_reason(partition: EspDefaultNvsPartition, reason: &str) -> Result<()> {
+    let mut reason = reason.to_string();
+    truncate(&mut reason);
+    let mut nvs = EspNvs::<NvsDefault>::new(partition, NVS_NAMESPACE, true)?;
+    nvs.set_str(NVS_KEY_RESTART_REASON, &reason)?;
+    Ok(())
+}
+
+/// Reads a stored message. A broken entry is only logged since it must not prevent booting.
+fn read_message(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<String> {
+    // NVS needs room for the terminating NUL
+    let mut buffer = [0u8; PANIC_MESSAGE_MAX_SIZE + 1];
+    match nvs.get_str(key, &mut buffer) {
+        Ok(message) => message.map(str::to_string),
+        Err(err) => {
+            log::warn!("Failed to read {key}: {err}");
+            None
+        }
+    }
+}
+
+/// Shortens a message so it can be read back with its terminating NUL, on a char boundary.
+fn truncate(message: &mut String) {
+    let mut length = message.len().min(PANIC_MESSAGE_MAX_SIZE - 1);
+    while !message.is_char_boundary(length) {
+        length -= 1;
+    }
+    message.truncate(length);
+}
+
+pub fn get() -> Option<&'static Diagnostics> {
+    DIAGNOSTICS.get()
+}
+
+/// Collects the heap usage per capability and the stack high water marks of all FreeRTOS tasks.
+/// Needs `CONFIG_FREERTOS_USE_TRACE_FACILITY` for the task list.
+pub fn memory() -> MemoryDiagnostics {
+    let heap = HEAP_CAPABILITIES
+        .iter()
+        .map(|&(capability, caps)| unsafe {
+            HeapDiagnostics {
+                capability,
+                free_bytes: heap_caps_get_free_size(caps),
+                minimum_free_bytes: heap_caps_get_minimum_free_size(caps),
+                largest_free_block_bytes: heap_caps_get_largest_free_block(caps),
+            }
+        })
+        .collect();
+
+    let capacity = unsafe { uxTaskGetNumberOfTasks() } as usize + TASK_STATUS_SLACK;
+    let mut task_statuses = Vec::<TaskStatus_t>::with_capacity(capacity);
+    unsafe {
+        let count = uxTaskGetSystemState(
+            task_statuses.as_mut_ptr(),
+            capacity as _,
+            core::ptr::null_mut(),
+        );
+        task_statuses.set_len(count as usize);
+    }
+
+    let mut tasks = task_statuses
+        .iter()
+        .map(|status| TaskDiagnostics {
+            name: unsafe { CStr::from_ptr(status.pcTaskName) }
+                .to_string_lossy()
+                .into_owned(),
+            priority: status.uxCurrentPriority as u32,
+            // ESP-IDF measures stacks in bytes instead of words
+            stack_high_water_mark_bytes: status.usStackHighWaterMark as u32,
+        })
+        .collect::<Vec<_>>();
+    tasks.sort_by_key(|task| task.stack_high_water_mark_bytes);
+
+    MemoryDiagnostics { heap, tasks }
+}
+
+fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+fn store_panic_record(info: &panic::PanicHookInfo) {
+    // Do not allocate here since the heap might be the reason for the panic
+    let mut writer = PanicWriter {
+        buffer: [0; PANIC_MESSAGE_MAX_SIZE],
+        length: 0,
+    };
+    let _ = fmt::write(&mut writer, format_args!("{info}"));
+
+    unsafe {
+        addr_of_mut!(PANIC_RECORD).write(MaybeUninit::new(PanicRecord {
+            magic: PANIC_RECORD_MAGIC,
+            length: writer.length,
+            message: writer.buffer,
+        }));
+    }
+}
+
+fn take_panic_record() -> Option<String> {
+    let record = unsafe {
+        let record = addr_of_mut!(PANIC_RECORD);
+        // Every bit pattern is a valid record, the magic tells whether it was written by us
+        let value = (*record).assume_init();
+        (*record).assume_init_mut().magic = 0;
+        value
+    };
+
+    if record.magic != PANIC_RECORD_MAGIC || record.length > PANIC_MESSAGE_MAX_SIZE {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&record.message[..record.length]).into_owned())
+}
+
+struct PanicWriter {
+    buffer: [u8; PANIC_MESSAGE_MAX_SIZE],
+    length: usize,
+}
+
+impl fmt::Write for PanicWriter {
+    fn write_str(&mut self, value: &str) -> fmt::Result {
+        // Truncate overlong messages
+        let remaining = PANIC_MESSAGE_MAX_SIZE - self.length;
+        let length = value.len().min(remaining);
+        self.buffer[self.length..self.length + length].copy_from_slice(&value.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
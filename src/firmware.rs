@@ -0,0 +1,88 @@
+use core::ffi::CStr;
+use esp_idf_svc::sys::{esp_app_get_description, CONFIG_IDF_FIRMWARE_CHIP_ID};
+use serde::Serialize;
+
+const IMAGE_HEADER_LENGTH: usize = 24;
+const SEGMENT_HEADER_LENGTH: usize = 8;
+const APP_DESCRIPTION_LENGTH: usize = 256;
+/// Bytes needed from the start of an image to read its app description.
+pub const HEADER_LENGTH: usize =
+    IMAGE_HEADER_LENGTH + SEGMENT_HEADER_LENGTH + APP_DESCRIPTION_LENGTH;
+
+const IMAGE_MAGIC: u8 = 0xe9;
+const APP_DESCRIPTION_MAGIC: u32 = 0xabcd_5432;
+const CHIP_ID_OFFSET: usize = 12;
+// Offsets inside of `esp_app_desc_t`
+const VERSION_OFFSET: usize = 16;
+const PROJECT_NAME_OFFSET: usize = 48;
+const TIME_OFFSET: usize = 80;
+const DATE_OFFSET: usize = 96;
+const IDF_VERSION_OFFSET: usize = 112;
+const VERSION_LENGTH: usize = 32;
+const PROJECT_NAME_LENGTH: usize = 32;
+const TIME_LENGTH: usize = 16;
+const DATE_LENGTH: usize = 16;
+const IDF_VERSION_LENGTH: usize = 32;
+
+/// Metadata of a firmware image taken from its `esp_image_header_t` and `esp_app_desc_t`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AppDescription {
+    pub chip_id: u16,
+    pub version: String,
+    pub project_name: String,
+    pub build_date: String,
+    pub build_time: String,
+    pub idf_version: String,
+}
+
+impl AppDescription {
+    /// Parses the start of an image, returns `None` if it is no ESP application image.
+    pub fn parse(header: &[u8]) -> Option<Self> {
+        if header.len() < HEADER_LENGTH || header[0] != IMAGE_MAGIC {
+            return None;
+        }
+
+        let chip_id = u16::from_le_bytes([header[CHIP_ID_OFFSET], header[CHIP_ID_OFFSET + 1]]);
+        let description = &header[IMAGE_HEADER_LENGTH + SEGMENT_HEADER_LENGTH..];
+        let magic = u32::from_le_bytes(description[..4].try_into().ok()?);
+        if magic != APP_DESCRIPTION_MAGIC {
+            return None;
+        }
+
+        Some(Self {
+            chip_id,
+            version: c_string(&description[VERSION_OFFSET..][..VERSION_LENGTH]),
+            project_name: c_string(&description[PROJECT_NAME_OFFSET..][..PROJECT_NAME_LENGTH]),
+            build_date: c_string(&description[DATE_OFFSET..][..DATE_LENGTH]),
+            build_time: c_string(&description[TIME_OFFSET..][..TIME_LENGTH]),
+            idf_version: c_string(&description[IDF_VERSION_OFFSET..][..IDF_VERSION_LENGTH]),
+        })
+    }
+
+    /// Description of the firmware which is currently running.
+    pub fn running() -> Self {
+        let description = unsafe { &*esp_app_get_description() };
+        let string = |value: &[core::ffi::c_char]| {
+            unsafe { CStr::from_ptr(value.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        Self {
+            chip_id: CONFIG_IDF_FIRMWARE_CHIP_ID as u16,
+            version: string(&description.version),
+            project_name: string(&description.project_name),
+            build_date: string(&description.date),
+            build_time: string(&description.time),
+            idf_version: string(&description.idf_ver),
+        }
+    }
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
@@ -0,0 +1,42 @@
+const BASE: u8 = 16;
+const ENCODING_TABLE: [char; BASE as usize] = [
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f',
+];
+
+pub fn encode(input: &[u8]) -> String {
+    let mut result = String::with_capacity(input.len() * 2);
+    for byte in input {
+        result.push(ENCODING_TABLE[(byte / BASE) as usize]);
+        result.push(ENCODING_TABLE[(byte % BASE) as usize]);
+    }
+    result
+}
+
+/// Decodes exactly `N` bytes, returns `None` on a length mismatch or invalid characters.
+/// Usable in constants, e.g. to check keys passed at build time.
+pub const fn decode<const N: usize>(input: &str) -> Option<[u8; N]> {
+    let input = input.as_bytes();
+    if input.len() != N * 2 {
+        return None;
+    }
+
+    let mut result = [0; N];
+    let mut index = 0;
+    while index < N {
+        let (Some(high), Some(low)) = (digit(input[index * 2]), digit(input[index * 2 + 1])) else {
+            return None;
+        };
+        result[index] = high * BASE + low;
+        index += 1;
+    }
+    Some(result)
+}
+
+const fn digit(character: u8) -> Option<u8> {
+    match character {
+        b'0'..=b'9' => Some(character - b'0'),
+        b'a'..=b'f' => Some(character - b'a' + 10),
+        b'A'..=b'F' => Some(character - b'A' + 10),
+        _ => None,
+    }
+}
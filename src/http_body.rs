@@ -0,0 +1,492 @@
+use esp_idf_svc::io::{Error, ErrorKind, ErrorType, EspIOError, Read};
+use miniz_oxide::{
+    inflate::stream::{inflate, InflateState},
+    DataFormat, MZError, MZFlush, MZStatus,
+};
+use thiserror::Error;
+
+const LINE_MAX_LENGTH: usize = 128;
+const PART_HEADERS_MAX_LENGTH: usize = 1024;
+// Form fields after the file part are discarded up to this size
+const EPILOGUE_MAX_LENGTH: usize = 4096;
+const FILL_SIZE: usize = 1024;
+const CRLF: &[u8] = b"\r\n";
+const HEADERS_END: &[u8] = b"\r\n\r\n";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const GZIP_HEADER_LENGTH: usize = 10;
+const GZIP_HEADER_MAX_LENGTH: usize = 1024;
+const GZIP_TRAILER_LENGTH: usize = 8;
+const GZIP_FLAG_HEADER_CRC: u8 = 0x02;
+const GZIP_FLAG_EXTRA: u8 = 0x04;
+const GZIP_FLAG_NAME: u8 = 0x08;
+const GZIP_FLAG_COMMENT: u8 = 0x10;
+
+#[derive(Error, Debug)]
+pub enum BodyError {
+    #[error(transparent)]
+    Io(#[from] EspIOError),
+    #[error("Malformed {0} body")]
+    Malformed(&'static str),
+}
+
+impl Error for BodyError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            BodyError::Io(err) => err.kind(),
+            BodyError::Malformed(_) => ErrorKind::InvalidData,
+        }
+    }
+}
+
+/// Adapts a reader like a request with a Content-Length to [`BodyError`].
+pub struct RawBody<R>(pub R);
+
+impl<R: ErrorType<Error = EspIOError>> ErrorType for RawBody<R> {
+    type Error = BodyError;
+}
+
+impl<R: Read<Error = EspIOError>> Read for RawBody<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.0.read(buffer)?)
+    }
+}
+
+enum ChunkedState {
+    Size,
+    Data,
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+/// Decodes a request body sent with `Transfer-Encoding: chunked`,
+/// which esp_http_server does not support on its own.
+/// Reads time out like any other request body read.
+pub struct ChunkedBody<R> {
+    inner: R,
+    state: ChunkedState,
+    remaining: usize,
+    // Kept between reads so a timeout does not lose a partially received line
+    line: heapless::Vec<u8, LINE_MAX_LENGTH>,
+}
+
+impl<R: Read<Error = EspIOError>> ChunkedBody<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: ChunkedState::Size,
+            remaining: 0,
+            line: heapless::Vec::new(),
+        }
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, BodyError> {
+        Ok(self.inner.read(buffer)?)
+    }
+
+    /// Completes the current line and returns it without the line ending.
+    fn read_line(&mut self) -> Result<heapless::Vec<u8, LINE_MAX_LENGTH>, BodyError> {
+        while !self.line.ends_with(CRLF) {
+            let mut byte = [0];
+            if self.receive(&mut byte)? == 0 {
+                return Err(BodyError::Malformed("chunked"));
+            }
+            self.line
+                .push(byte[0])
+                .map_err(|_| BodyError::Malformed("chunked"))?;
+        }
+
+        let mut line = core::mem::take(&mut self.line);
+        line.truncate(line.len() - CRLF.len());
+        Ok(line)
+    }
+}
+
+impl<R: Read<Error = EspIOError>> ErrorType for ChunkedBody<R> {
+    type Error = BodyError;
+}
+
+impl<R: Read<Error = EspIOError>> Read for ChunkedBody<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        loop {
+            match self.state {
+                ChunkedState::Size => {
+                    let line = self.read_line()?;
+                    // Chunk extensions after ';' are ignored
+                    let size = core::str::from_utf8(&line)
+                        .ok()
+                        .and_then(|line| line.split(';').next())
+                        .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                        .ok_or(BodyError::Malformed("chunked"))?;
+
+                    self.remaining = size;
+                    self.state = if size == 0 {
+                        ChunkedState::Trailer
+                    } else {
+                        ChunkedState::Data
+                    };
+                }
+                ChunkedState::Data => {
+                    let length = buffer.len().min(self.remaining);
+                    let bytes_read = self.receive(&mut buffer[..length])?;
+                    if bytes_read == 0 {
+                        return Err(BodyError::Malformed("chunked"));
+                    }
+                    self.remaining -= bytes_read;
+                    if self.remaining == 0 {
+                        self.state = ChunkedState::DataEnd;
+                    }
+                    return Ok(bytes_read);
+                }
+                ChunkedState::DataEnd => {
+                    if !self.read_line()?.is_empty() {
+                        return Err(BodyError::Malformed("chunked"));
+                    }
+                    self.state = ChunkedState::Size;
+                }
+                ChunkedState::Trailer => {
+                    // Trailer fields are ignored until the empty line
+                    if self.read_line()?.is_empty() {
+                        self.state = ChunkedState::Done;
+                    }
+                }
+                ChunkedState::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+enum MultipartState {
+    Preamble,
+    Delimiter,
+    Headers,
+    Skipping,
+    Content,
+    Epilogue { discarded: usize },
+    Done,
+}
+
+/// Streams the content of the first file part of a `multipart/form-data` body.
+pub struct MultipartBody<R> {
+    inner: R,
+    // "\r\n--" followed by the boundary
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: MultipartState,
+}
+
+impl<R: Read<Error = BodyError>> MultipartBody<R> {
+    pub fn new(inner: R, boundary: &str) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        Self {
+            inner,
+            delimiter,
+            // The first delimiter is not preceded by a line break
+            buffer: CRLF.to_vec(),
+            state: MultipartState::Preamble,
+        }
+    }
+
+    fn fill_or_fail(&mut self) -> Result<(), BodyError> {
+        if fill(&mut self.inner, &mut self.buffer)? {
+            Ok(())
+        } else {
+            Err(BodyError::Malformed("multipart"))
+        }
+    }
+}
+
+impl<R: Read<Error = BodyError>> ErrorType for MultipartBody<R> {
+    type Error = BodyError;
+}
+
+impl<R: Read<Error = BodyError>> Read for MultipartBody<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        loop {
+            match self.state {
+                MultipartState::Preamble | MultipartState::Skipping => {
+                    match find(&self.buffer, &self.delimiter) {
+                        Some(index) => {
+                            self.buffer.drain(..index + self.delimiter.len());
+                            self.state = MultipartState::Delimiter;
+                        }
+                        None => {
+                            // Keep what could be the start of a delimiter
+                            let keep = self.delimiter.len() - 1;
+                            self.buffer.drain(..self.buffer.len().saturating_sub(keep));
+                            self.fill_or_fail()?;
+                        }
+                    }
+                }
+                MultipartState::Delimiter => {
+                    if self.buffer.len() < CRLF.len() {
+                        self.fill_or_fail()?;
+                    } else if self.buffer.starts_with(CRLF) {
+                        self.buffer.drain(..CRLF.len());
+                        self.state = MultipartState::Headers;
+                    } else {
+                        // Either garbage or the close delimiter of a body without a file part
+                        return Err(BodyError::Malformed("multipart"));
+                    }
+                }
+                MultipartState::Headers => match find(&self.buffer, HEADERS_END) {
+                    Some(index) => {
+                        let headers = String::from_utf8_lossy(&self.buffer[..index]);
+                        let is_file = headers.lines().any(|header| {
+                            header
+                                .to_ascii_lowercase()
+                                .starts_with("content-disposition")
+                                && header.contains("filename=")
+                        });
+                        self.buffer.drain(..index + HEADERS_END.len());
+                        // Form fields are not of interest
+                        self.state = if is_file {
+                            MultipartState::Content
+                        } else {
+                            MultipartState::Skipping
+                        };
+                    }
+                    None if self.buffer.len() > PART_HEADERS_MAX_LENGTH => {
+                        return Err(BodyError::Malformed("multipart"));
+                    }
+                    None => self.fill_or_fail()?,
+                },
+                MultipartState::Content => {
+                    // Everything up to a possible delimiter belongs to the file
+                    let available = match find(&self.buffer, &self.delimiter) {
+                        Some(0) => {
+                            self.state = MultipartState::Epilogue { discarded: 0 };
+                            continue;
+                        }
+                        Some(index) => index,
+                        None => self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+                    };
+
+                    if available == 0 {
+                        self.fill_or_fail()?;
+                        continue;
+                    }
+                    let length = available.min(buffer.len());
+                    buffer[..length].copy_from_slice(&self.buffer[..length]);
+                    self.buffer.drain(..length);
+                    return Ok(length);
+                }
+                MultipartState::Epilogue { discarded } => {
+                    // Consume the rest of the body so it is not left on a kept alive connection
+                    let discarded = discarded + self.buffer.len();
+                    if discarded > EPILOGUE_MAX_LENGTH {
+                        return Err(BodyError::Malformed("multipart"));
+                    }
+                    self.buffer.clear();
+                    self.state = if fill(&mut self.inner, &mut self.buffer)? {
+                        MultipartState::Epilogue { discarded }
+                    } else {
+                        MultipartState::Done
+                    };
+                }
+                MultipartState::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+enum GzipState {
+    Detect,
+    Header,
+    Inflate,
+    Trailer,
+    Passthrough,
+    Done,
+}
+
+/// Decompresses a gzip body on the fly, any other body is passed through unchanged.
+/// Needs about 43 KB of heap for the inflate state while decompressing.
+pub struct GzipBody<R> {
+    inner: R,
+    state: GzipState,
+    // Boxed since it is too large for the stack of the HTTP server
+    inflate: Option<Box<InflateState>>,
+    buffer: Vec<u8>,
+    // Decompressed size modulo 2^32 as stored in the trailer
+    size: u32,
+}
+
+impl<R: Read<Error = BodyError>> GzipBody<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: GzipState::Detect,
+            inflate: None,
+            buffer: Vec::new(),
+            size: 0,
+        }
+    }
+
+    fn fill_or_fail(&mut self) -> Result<(), BodyError> {
+        if fill(&mut self.inner, &mut self.buffer)? {
+            Ok(())
+        } else {
+            Err(BodyError::Malformed("gzip"))
+        }
+    }
+}
+
+impl<R: Read<Error = BodyError>> ErrorType for GzipBody<R> {
+    type Error = BodyError;
+}
+
+impl<R: Read<Error = BodyError>> Read for GzipBody<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        loop {
+            match self.state {
+                GzipState::Detect => {
+                    if self.buffer.len() >= GZIP_MAGIC.len() {
+                        self.state = if self.buffer.starts_with(GZIP_MAGIC) {
+                            log::info!("Decompressing gzip body");
+                            GzipState::Header
+                        } else {
+                            GzipState::Passthrough
+                        };
+                    } else if !fill(&mut self.inner, &mut self.buffer)? {
+                        self.state = GzipState::Passthrough;
+                    }
+                }
+                GzipState::Header => match gzip_header_length(&self.buffer) {
+                    Some(_) if self.buffer[2] != GZIP_METHOD_DEFLATE => {
+                        return Err(BodyError::Malformed("gzip"));
+                    }
+                    Some(length) => {
+                        self.buffer.drain(..length);
+                        self.inflate = Some(InflateState::new_boxed(DataFormat::Raw));
+                        self.state = GzipState::Inflate;
+                    }
+                    None if self.buffer.len() > GZIP_HEADER_MAX_LENGTH => {
+                        return Err(BodyError::Malformed("gzip"));
+                    }
+                    None => self.fill_or_fail()?,
+                },
+                GzipState::Inflate => {
+                    if self.buffer.is_empty() {
+                        self.fill_or_fail()?;
+                    }
+                    let Some(state) = self.inflate.as_mut() else {
+                        return Err(BodyError::Malformed("gzip"));
+                    };
+
+                    let result = inflate(state, &self.buffer, buffer, MZFlush::None);
+                    self.buffer.drain(..result.bytes_consumed);
+                    self.size = self.size.wrapping_add(result.bytes_written as u32);
+                    match result.status {
+                        Ok(MZStatus::StreamEnd) => {
+                            // Free the inflate state before the update completes
+                            self.inflate = None;
+                            self.state = GzipState::Trailer;
+                        }
+                        Ok(_) | Err(MZError::Buf) => {
+                            if result.bytes_written == 0 && result.bytes_consumed == 0 {
+                                self.fill_or_fail()?;
+                            }
+                        }
+                        Err(_) => return Err(BodyError::Malformed("gzip")),
+                    }
+                    if result.bytes_written > 0 {
+                        return Ok(result.bytes_written);
+                    }
+                }
+                GzipState::Trailer => {
+                    if self.buffer.len() < GZIP_TRAILER_LENGTH {
+                        self.fill_or_fail()?;
+                        continue;
+                    }
+                    // The CRC-32 is not checked since the image carries its own checksum,
+                    // which is verified when the update completes
+                    let size = u32::from_le_bytes([
+                        self.buffer[4],
+                        self.buffer[5],
+                        self.buffer[6],
+                        self.buffer[7],
+                    ]);
+                    if size != self.size {
+                        return Err(BodyError::Malformed("gzip"));
+                    }
+                    self.state = GzipState::Done;
+                }
+                GzipState::Passthrough => {
+                    if self.buffer.is_empty() {
+                        return self.inner.read(buffer);
+                    }
+                    let length = self.buffer.len().min(buffer.len());
+                    buffer[..length].copy_from_slice(&self.buffer[..length]);
+                    self.buffer.drain(..length);
+                    return Ok(length);
+                }
+                GzipState::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Extracts the boundary from a `multipart/form-data` content type.
+pub fn multipart_boundary(content_type: &str) -> Option<&str> {
+    let mut parameters = content_type.split(';');
+    let media_type = parameters.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    parameters
+        .filter_map(|parameter| parameter.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, boundary)| boundary.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+}
+
+/// Length of the gzip header at the start of the buffer, `None` if it is not complete yet.
+fn gzip_header_length(buffer: &[u8]) -> Option<usize> {
+    let flags = *buffer.get(3)?;
+    let mut length = GZIP_HEADER_LENGTH;
+    if buffer.len() < length {
+        return None;
+    }
+    if flags & GZIP_FLAG_EXTRA != 0 {
+        let extra_length = u16::from_le_bytes([*buffer.get(length)?, *buffer.get(length + 1)?]);
+        length += 2 + extra_length as usize;
+    }
+    // File name and comment are zero terminated
+    for flag in [GZIP_FLAG_NAME, GZIP_FLAG_COMMENT] {
+        if flags & flag != 0 {
+            length += buffer.get(length..)?.iter().position(|byte| *byte == 0)? + 1;
+        }
+    }
+    if flags & GZIP_FLAG_HEADER_CRC != 0 {
+        length += 2;
+    }
+    (buffer.len() >= length).then_some(length)
+}
+
+/// Reads the rest of a body, e.g. parts after the uploaded file, so it is not left on the
+/// connection.
+pub fn drain<R: Read + ?Sized>(reader: &mut R) -> Result<(), R::Error> {
+    let mut chunk = [0; FILL_SIZE];
+    while reader.read(&mut chunk)? > 0 {}
+    Ok(())
+}
+
+/// Appends the next bytes of the inner body, returns `false` if it ended.
+fn fill<R: Read<Error = BodyError>>(
+    inner: &mut R,
+    buffer: &mut Vec<u8>,
+) -> Result<bool, BodyError> {
+    let mut chunk = [0; FILL_SIZE];
+    let bytes_read = inner.read(&mut chunk)?;
+    buffer.extend_from_slice(&chunk[..bytes_read]);
+    Ok(bytes_read > 0)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -1,4 +1,17 @@
-use crate::uart::Uart;
+use crate::{
+    auth::{Auth, AuthError, RevokeError, Scope, TokenInfo},
+    diagnostics, hex,
+    http_body::{self, BodyError, ChunkedBody, GzipBody, MultipartBody, RawBody},
+    logger::{self, LevelsError, LogLevels},
+    metrics, openapi,
+    ota::{self, OtaError, Updater},
+    rate_limit::{self, RateLimits},
+    tls::{self, Credentials, TlsError},
+    uart::Uart,
+    update_client::UpdateTrigger,
+    upload::{self, Upload, UploadError},
+    watchdog::Watchdog,
+};
 use am03127::{
     page_content::{
         formatting::{Clock as ClockFormat, ColumnStart, Font},
@@ -7,45 +20,97 @@ use am03127::{
     real_time_clock::RealTimeClock,
 };
 use anyhow::Result;
-use core::fmt::Debug;
+use core::mem::{size_of, MaybeUninit};
 use embedded_svc::http::Headers;
 use esp_idf_svc::{
-    hal::reset::restart,
     http::{
         server::{Configuration, EspHttpConnection, EspHttpServer, Request},
         Method,
     },
-    io::Write,
+    io::{EspIOError, Read, Write},
+    nvs::EspDefaultNvsPartition,
     ota::EspOta,
-    timer::EspTimerService,
+    sys::{self, EspError},
+    tls::X509,
 };
-use heapless::{String, Vec};
+use heapless::String;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::Duration;
 use std::{
-    str::FromStr,
+    borrow::Cow,
     sync::{Arc, Mutex},
 };
 use thiserror::Error;
 
 static HTML: &str = include_str!("index.html");
 
+const STATUS_CODE_ACCEPTED: u16 = 202;
+const STATUS_CODE_PERMANENT_REDIRECT: u16 = 308;
 const STATUS_CODE_BAD_REQUEST: u16 = 400;
+const STATUS_CODE_UNAUTHORIZED: u16 = 401;
+const STATUS_CODE_FORBIDDEN: u16 = 403;
+const STATUS_CODE_NOT_FOUND: u16 = 404;
+const STATUS_CODE_REQUEST_TIMEOUT: u16 = 408;
+const STATUS_CODE_CONFLICT: u16 = 409;
 const STATUS_CODE_LENGTH_REQUIRED: u16 = 411;
 const STATUS_CODE_REQUEST_ENTITY_TO_LARGE: u16 = 413;
 const STATUS_CODE_UNSUPPORTED_MEDIA_TYPE: u16 = 415;
+const STATUS_CODE_TOO_MANY_REQUESTS: u16 = 429;
+const STATUS_CODE_INTERNAL_SERVER_ERROR: u16 = 500;
+const STATUS_CODE_BAD_GATEWAY: u16 = 502;
+const STATUS_CODE_SERVICE_UNAVAILABLE: u16 = 503;
 
-const HTTP_SERVER_STACK_SIZE: usize = OTA_CHNUNK_SIZE + 1024 * 8;
-const HTTP_SERVER_MAX_RESPONSE_BODY_SIZE: usize = 1024;
-const OTA_PARTITION_SIZE: usize = 0x1f0000;
-const OTA_CHNUNK_SIZE: usize = 1024 * 8;
+const API_PREFIX: &str = "/api/v1";
+// The TLS handshake runs on the server task as well
+const HTTP_SERVER_STACK_SIZE: usize = upload::CHUNK_SIZE + 1024 * 12;
+const REDIRECT_SERVER_STACK_SIZE: usize = 1024 * 4;
+// Has to differ from the one of the HTTPS server
+const REDIRECT_SERVER_CONTROL_PORT: u16 = 32769;
+/// Serve redirects to HTTPS on port 80 if set to `true`
+const HTTP_REDIRECT: Option<&str> = option_env!("HTTP_REDIRECT");
+const TLS_CERTIFICATE_MAX_SIZE: usize = 1024 * 8;
+/// Bytes of text the panel accepts at once
+pub const TEXT_MAX_LENGTH: usize = 32;
+/// Capacity of the hostname reported by the network interface
+pub const HOSTNAME_MAX_LENGTH: usize = 30;
+// Largest bodies accepted per route
+const TEXT_BODY_MAX_SIZE: usize = 512;
+const CLOCK_BODY_MAX_SIZE: usize = 256;
+const LOG_LEVEL_BODY_MAX_SIZE: usize = 1024;
+const RATE_LIMIT_BODY_MAX_SIZE: usize = 256;
+const TOKEN_BODY_MAX_SIZE: usize = 256;
+const OTA_REBOOT_DELAY: Duration = Duration::from_secs(5);
 const CONTENT_TYPE_OCTET_STEAM: &str = "application/octet-stream";
 const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_TEXT: &str = "text/plain";
+const CONTENT_TYPE_FORM: &str = "application/x-www-form-urlencoded";
+const CONTENT_TYPE_HTML: &str = "text/html";
+const CONTENT_TYPE_METRICS: &str = "text/plain; version=0.0.4";
+const CONTENT_TYPE_PEM: &str = "application/x-pem-file";
+const HEADER_FIRMWARE_SHA256: &str = "X-Firmware-SHA256";
+const QUERY_PARAMETER_SHA256: &str = "sha256";
+const HEADER_FIRMWARE_SIGNATURE: &str = "X-Firmware-Signature";
+const QUERY_PARAMETER_SIGNATURE: &str = "signature";
+const QUERY_PARAMETER_DISPLAY: &str = "display";
+const QUERY_PARAMETER_FORCE: &str = "force";
+const QUERY_PARAMETER_MESSAGE: &str = "msg";
+const HEADER_TRANSFER_ENCODING: &str = "Transfer-Encoding";
+const HEADER_CONTENT_ENCODING: &str = "Content-Encoding";
+const HEADER_AUTHORIZATION: &str = "Authorization";
+const HEADER_WWW_AUTHENTICATE: &str = "WWW-Authenticate";
+const HEADER_RETRY_AFTER: &str = "Retry-After";
 
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct Status {
-    pub hostname: String<30>,
+    pub hostname: String<HOSTNAME_MAX_LENGTH>,
     pub version: String<24>,
+    pub slot: String<16>,
+    pub slot_state: &'static str,
+    pub reset_reason: &'static str,
+    pub boot_count: u32,
+    /// Message and location of the last Rust panic, if there ever was one
+    pub last_panic: Option<&'static str>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize)]
@@ -61,7 +126,7 @@ pub struct Clock {
 #[derive(Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct FormattedText {
-    pub text: String<32>,
+    pub text: String<TEXT_MAX_LENGTH>,
     #[serde(default)]
     pub leading: Leading,
     #[serde(default)]
@@ -70,323 +135,1278 @@ pub struct FormattedText {
     pub waiting_mode_and_speed: WaitingModeAndSpeed,
 }
 
-pub fn init(hostname: String<30>, uart: Uart) -> Result<EspHttpServer<'static>> {
-    log::info!("Initialize http server");
+/// Body of every error response.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ErrorResponse<'a> {
+    /// Machine readable error code
+    pub error: &'static str,
+    pub message: std::string::String,
+    /// Request field causing the error, if any
+    pub field: Option<&'a str>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokenCreation {
+    pub scopes: std::vec::Vec<Scope>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CreatedToken {
+    /// Only returned once, the device stores nothing but its digest
+    pub token: std::string::String,
+    #[serde(flatten)]
+    pub info: TokenInfo,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokenRevocation {
+    pub id: std::string::String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LogLevelUpdate {
+    #[serde(flatten)]
+    pub levels: LogLevels,
+    /// Targets falling back to the global level
+    #[serde(default)]
+    pub clear: std::vec::Vec<std::string::String>,
+    /// Store the resulting levels in NVS so they survive a reboot
+    #[serde(default)]
+    pub persist: bool,
+}
+
+pub fn init(
+    hostname: String<HOSTNAME_MAX_LENGTH>,
+    uart: Uart,
+    nvs_partition: EspDefaultNvsPartition,
+    update_trigger: Option<UpdateTrigger>,
+    auth: Auth,
+    credentials: Credentials,
+) -> Result<EspHttpServer<'static>> {
+    log::info!("Initialize https server");
+    // Wrap the Uart in Arc<Mutex<>> for shared ownership
+    let uart = Arc::new(Mutex::new(uart));
+
+    let mut routes = Routes::default();
+    add_update_handler(&mut routes, Arc::clone(&uart), auth.clone());
+    add_update_progress_handler(&mut routes, auth.clone());
+    add_update_slots_handler(&mut routes, auth.clone());
+    add_update_slots_switch_handler(&mut routes, auth.clone());
+    if let Some(update_trigger) = update_trigger {
+        add_update_check_handler(&mut routes, update_trigger, auth.clone());
+    }
+
+    // Pass clones of the Arc to each handler
+    add_text_handler(&mut routes, Arc::clone(&uart), auth.clone());
+    add_clock_handler(&mut routes, Arc::clone(&uart), auth.clone());
+    add_status_handler(&mut routes, hostname);
+    add_metrics_handler(&mut routes, auth.clone());
+    add_diagnostics_handler(&mut routes, auth.clone());
+    add_log_level_handler(&mut routes, nvs_partition.clone(), auth.clone());
+    add_rate_limit_handler(&mut routes, nvs_partition.clone(), auth.clone());
+    add_tls_certificate_handler(&mut routes, nvs_partition, auth.clone());
+    add_token_handlers(&mut routes, auth);
+    add_web_page_handler(&mut routes);
+    add_openapi_handler(&mut routes)?;
+
     let configuration = Configuration {
         stack_size: HTTP_SERVER_STACK_SIZE,
+        server_certificate: Some(X509::pem_until_nul(credentials.certificate)),
+        private_key: Some(X509::pem_until_nul(credentials.private_key)),
+        max_uri_handlers: routes.uri_handler_count(),
+        // Path parameters are matched with wildcard routes like `/tokens/*`
+        uri_match_wildcard: true,
         ..Default::default()
     };
+    let mut server = EspHttpServer::new(&configuration)?;
+    routes.mount(&mut server)?;
+    Ok(server)
+}
 
-    // Wrap the Uart in Arc<Mutex<>> for shared ownership
-    let uart = Arc::new(Mutex::new(uart));
+/// Starts a plain HTTP server redirecting every request to HTTPS, if enabled.
+pub fn init_redirect(hostname: &str) -> Result<Option<EspHttpServer<'static>>> {
+    if HTTP_REDIRECT != Some("true") {
+        return Ok(None);
+    }
+    log::info!("Initialize http redirect server");
+    let configuration = Configuration {
+        stack_size: REDIRECT_SERVER_STACK_SIZE,
+        ctrl_port: REDIRECT_SERVER_CONTROL_PORT,
+        uri_match_wildcard: true,
+        ..Default::default()
+    };
 
     let mut server = EspHttpServer::new(&configuration)?;
-    add_update_handler(&mut server)?;
+    let default_host = format!("{hostname}.local");
+    for method in [
+        Method::Get,
+        Method::Head,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+    ] {
+        let default_host = default_host.clone();
+        server.fn_handler::<anyhow::Error, _>("/*", method, move |request| {
+            let host = request
+                .header("Host")
+                .map(|host| host.split(':').next().unwrap_or(host))
+                .unwrap_or(&default_host);
+            let location = format!("https://{host}{}", request.uri());
+            // 308 keeps the method and body, unlike 301
+            request.into_response(
+                STATUS_CODE_PERMANENT_REDIRECT,
+                None,
+                &[("Location", &location)],
+            )?;
+            Ok(())
+        })?;
+    }
+    Ok(Some(server))
+}
 
-    // Pass clones of the Arc to each handler
-    add_text_handler(&mut server, Arc::clone(&uart))?;
-    add_clock_handler(&mut server, Arc::clone(&uart))?;
-    add_status_handler(&mut server, hostname)?;
-    add_web_page_handler(&mut server)?;
+/// Handler shared by all paths a route is mounted at.
+type Handler = Arc<
+    dyn for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), CustomError> + Send + Sync,
+>;
 
-    Ok(server)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mount {
+    /// Below the API prefix only
+    Versioned,
+    /// Below the API prefix and at the path used before the API was versioned
+    WithLegacyAlias,
+    /// At the path itself, e.g. for the web page
+    Root,
+}
+
+struct Route {
+    path: &'static str,
+    method: Method,
+    mount: Mount,
+    handler: Handler,
+}
+
+/// Table of all routes. Collected first so the server can be sized for it.
+#[derive(Default)]
+struct Routes(std::vec::Vec<Route>);
+
+impl Routes {
+    /// Adds an API route, also reachable at its legacy path.
+    fn add<F>(&mut self, path: &'static str, method: Method, handler: F)
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), CustomError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.push(path, method, Mount::WithLegacyAlias, handler);
+    }
+
+    /// Adds an API route without a legacy path.
+    fn add_versioned<F>(&mut self, path: &'static str, method: Method, handler: F)
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), CustomError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.push(path, method, Mount::Versioned, handler);
+    }
+
+    fn add_page<F>(&mut self, path: &'static str, method: Method, handler: F)
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), CustomError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.push(path, method, Mount::Root, handler);
+    }
+
+    fn push<F>(&mut self, path: &'static str, method: Method, mount: Mount, handler: F)
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), CustomError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.0.push(Route {
+            path,
+            method,
+            mount,
+            handler: Arc::new(handler),
+        });
+    }
+
+    fn uris(route: &Route) -> impl Iterator<Item = std::string::String> + '_ {
+        let versioned = (route.mount != Mount::Root).then(|| format!("{API_PREFIX}{}", route.path));
+        let root = (route.mount != Mount::Versioned).then(|| route.path.to_string());
+        versioned.into_iter().chain(root)
+    }
+
+    /// Path and method of every route below the API prefix.
+    fn endpoints(&self) -> std::vec::Vec<(&'static str, Method)> {
+        self.0
+            .iter()
+            .filter(|route| route.mount != Mount::Root)
+            .map(|route| (route.path, route.method))
+            .collect()
+    }
+
+    /// Number of URI handlers the server has to provide room for.
+    fn uri_handler_count(&self) -> usize {
+        self.0.iter().map(|route| Self::uris(route).count()).sum()
+    }
+
+    fn mount(self, server: &mut EspHttpServer<'static>) -> Result<()> {
+        for route in &self.0 {
+            for uri in Self::uris(route) {
+                let handler = Arc::clone(&route.handler);
+                server.fn_handler::<CustomError, _>(&uri, route.method, move |request| {
+                    handler(request)
+                })?;
+            }
+        }
+        log::info!("Registered {} URI handlers", self.uri_handler_count());
+        Ok(())
+    }
+}
+
+/// Rejects requests without a bearer token granting the scope before they reach the handler.
+fn authorized<F>(
+    auth: Auth,
+    scope: Scope,
+    handler: F,
+) -> impl for<'r> Fn(&mut Request<&mut EspHttpConnection<'r>>) -> Result<Response, CustomError>
+       + Send
+       + Sync
+       + 'static
+where
+    F: for<'r> Fn(&mut Request<&mut EspHttpConnection<'r>>) -> Result<Response, CustomError>
+        + Send
+        + Sync
+        + 'static,
+{
+    authorized_with_scopes(auth, scope, move |request, _scopes| handler(request))
 }
 
-fn error_handling_wrapper<E, F>(
+/// Like [`authorized`] but passes every scope of the token to the handler.
+fn authorized_with_scopes<F>(
+    auth: Auth,
+    scope: Scope,
     handler: F,
-) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), E> + Send + 'static
+) -> impl for<'r> Fn(&mut Request<&mut EspHttpConnection<'r>>) -> Result<Response, CustomError>
+       + Send
+       + Sync
+       + 'static
 where
     F: for<'r> Fn(
             &mut Request<&mut EspHttpConnection<'r>>,
-        ) -> Result<Vec<u8, HTTP_SERVER_MAX_RESPONSE_BODY_SIZE>, CustomError>
+            &[Scope],
+        ) -> Result<Response, CustomError>
+        + Send
+        + Sync
+        + 'static,
+{
+    rate_limited(move |request| {
+        let ip = client_ip(request);
+        match auth.authorize(bearer_token(request), scope) {
+            Ok(scopes) => {
+                if let Some(ip) = ip {
+                    rate_limit::auth_succeeded(ip);
+                }
+                handler(request, &scopes)
+            }
+            Err(err) => {
+                if let (AuthError::Unauthenticated, Some(ip)) = (&err, ip) {
+                    rate_limit::auth_failed(ip);
+                }
+                Err(err.into())
+            }
+        }
+    })
+}
+
+/// Answers with 429 while the client exceeds its rate limit or is locked out.
+fn rate_limited<F>(
+    handler: F,
+) -> impl for<'r> Fn(&mut Request<&mut EspHttpConnection<'r>>) -> Result<Response, CustomError>
+       + Send
+       + Sync
+       + 'static
+where
+    F: for<'r> Fn(&mut Request<&mut EspHttpConnection<'r>>) -> Result<Response, CustomError>
         + Send
+        + Sync
         + 'static,
-    E: Debug,
 {
-    move |request| match handler(&mut request) {
-        Ok(body) => {
+    move |request| {
+        let Some(ip) = client_ip(request) else {
+            return handler(request);
+        };
+        match rate_limit::check(ip) {
+            Ok(()) => handler(request),
+            Err(retry_after) => Err(CustomError::RateLimited {
+                retry_after: retry_after.as_secs_f32().ceil().max(1.0) as u64,
+            }),
+        }
+    }
+}
+
+/// Address of the peer, IPv4 clients connected to the IPv6 socket are reported as IPv4.
+fn client_ip(request: &mut Request<&mut EspHttpConnection<'_>>) -> Option<IpAddr> {
+    let raw_request = request.connection().raw_connection().ok()?;
+    let socket = unsafe { sys::httpd_req_to_sockfd(raw_request) };
+    let mut address = MaybeUninit::<sys::sockaddr_storage>::zeroed();
+    let mut length = size_of::<sys::sockaddr_storage>() as sys::socklen_t;
+    if unsafe { sys::lwip_getpeername(socket, address.as_mut_ptr().cast(), &mut length) } != 0 {
+        return None;
+    }
+
+    let address = address.as_ptr();
+    match unsafe { (*address).ss_family } as u32 {
+        sys::AF_INET => {
+            let address = unsafe { &*address.cast::<sys::sockaddr_in>() };
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                address.sin_addr.s_addr,
+            ))))
+        }
+        sys::AF_INET6 => {
+            let address = unsafe { &*address.cast::<sys::sockaddr_in6>() };
+            let ip = Ipv6Addr::from(unsafe { address.sin6_addr.un.u8_addr });
+            Some(ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4))
+        }
+        _ => None,
+    }
+}
+
+fn bearer_token<'a>(request: &'a Request<&mut EspHttpConnection<'_>>) -> Option<&'a str> {
+    request
+        .header(HEADER_AUTHORIZATION)?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Sends the response of the handler. Errors are answered with the JSON error envelope.
+fn error_handling_wrapper<F>(
+    route: &'static str,
+    handler: F,
+) -> impl for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), CustomError>
+       + Send
+       + Sync
+       + 'static
+where
+    F: for<'r> Fn(&mut Request<&mut EspHttpConnection<'r>>) -> Result<Response, CustomError>
+        + Send
+        + Sync
+        + 'static,
+{
+    move |mut request| match handler(&mut request) {
+        Ok(Response::Status(status)) => {
+            metrics::http_request(route, status);
+            request.into_status_response(status)?;
+            Ok(())
+        }
+        Ok(Response::Body { content_type, body }) => {
+            metrics::http_request(route, 200);
             request
-                .into_ok_response()
-                .unwrap()
-                .write_all(&body)
-                .unwrap();
+                .into_response(200, None, &[("Content-Type", content_type)])?
+                .write_all(&body)?;
             Ok(())
         }
         Err(err) => {
-            log::error!("Handler error: {:?}", err);
-            let status = match err {
-                CustomError::Unknown => 500,
-                CustomError::InvalidContentType() => 415,
-                CustomError::Parsing(_) => 400,
-            };
+            let status = err.status();
+            if status >= STATUS_CODE_INTERNAL_SERVER_ERROR {
+                log::error!("Request to {route} failed: {err:?}");
+            } else {
+                log::warn!("Rejected request to {route}: {err}");
+            }
+            metrics::http_request(route, status);
 
+            let body = ErrorResponse {
+                error: err.code(),
+                message: err.to_string(),
+                field: err.field(),
+            };
+            // Serializing a struct of strings can not fail
+            let body = serde_json::to_vec(&body).unwrap_or_default();
+            let retry_after;
+            let mut headers = vec![("Content-Type", CONTENT_TYPE_JSON)];
+            match &err {
+                CustomError::Auth(_) => headers.push((HEADER_WWW_AUTHENTICATE, "Bearer")),
+                CustomError::RateLimited {
+                    retry_after: seconds,
+                } => {
+                    retry_after = seconds.to_string();
+                    headers.push((HEADER_RETRY_AFTER, &retry_after));
+                }
+                _ => {}
+            }
             request
-                .into_status_response(status)
-                .unwrap()
-                .write_all(b"")
-                .unwrap();
+                .into_response(status, None, &headers)?
+                .write_all(&body)?;
             Ok(())
         }
     }
 }
 
-fn add_web_page_handler(server: &mut EspHttpServer<'static>) -> Result<()> {
-    // Do not use the error wrapper here since we want not to be limited by the max body size.
-    server.fn_handler::<anyhow::Error, _>("/", Method::Get, |request| {
-        request.into_ok_response()?.write_all(HTML.as_bytes())?;
-        Ok(())
-    })?;
+fn add_web_page_handler(routes: &mut Routes) {
+    let handler = error_handling_wrapper("/", |_request| {
+        Ok(Response::Body {
+            content_type: CONTENT_TYPE_HTML,
+            body: Cow::Borrowed(HTML.as_bytes()),
+        })
+    });
+    routes.add_page("/", Method::Get, handler);
+}
+
+fn add_openapi_handler(routes: &mut Routes) -> Result<()> {
+    // Rendered once, the routes do not change at runtime
+    let document: &'static [u8] = openapi::document(API_PREFIX, &routes.endpoints())?.leak();
+    let handler = error_handling_wrapper(
+        "/api/openapi.json",
+        rate_limited(move |_request| {
+            Ok(Response::Body {
+                content_type: CONTENT_TYPE_JSON,
+                body: Cow::Borrowed(document),
+            })
+        }),
+    );
+    routes.add_page("/api/openapi.json", Method::Get, handler);
     Ok(())
 }
 
-fn add_clock_handler(server: &mut EspHttpServer<'static>, uart: Arc<Mutex<Uart>>) -> Result<()> {
+fn add_clock_handler(routes: &mut Routes, uart: Arc<Mutex<Uart>>, auth: Auth) {
     let uart_get = uart.clone();
-    server.fn_handler::<anyhow::Error, _>("/clock", Method::Get, move |request| {
-        log::info!("Display clock");
-        let message = format!(
-            "{}{}{}{}",
-            ClockFormat::Time,
-            Font::Narrow,
-            ColumnStart(41),
-            ClockFormat::Date
-        );
-        let command = PageContent::default().message(&message).command();
-
-        // Lock the UART to get exclusive access
-        // The lock is automatically released when uart_guard goes out of scope
-        let uart = uart_get
-            .lock()
-            .map_err(|e| anyhow::anyhow!("Failed to lock UART: {:?}", e))?;
-
-        // Write to the UART
-        uart.write(&command)?;
-
-        // Respond to the HTTP request
-        request.into_ok_response()?.write(&[])?;
-        Ok(())
-    })?;
+    // Changes what the panel shows despite being a GET
+    let handler = error_handling_wrapper(
+        "/clock",
+        authorized(auth.clone(), Scope::Content, move |_request| {
+            log::info!("Display clock");
+            let _watchdog = Watchdog::subscribe()?;
+            let message = format!(
+                "{}{}{}{}",
+                ClockFormat::Time,
+                Font::Narrow,
+                ColumnStart(41),
+                ClockFormat::Date
+            );
+            let command = PageContent::default().message(&message).command();
+            write_panel(&uart_get, &command)?;
+            Ok(Response::ok())
+        }),
+    );
+    routes.add("/clock", Method::Get, handler);
 
     let uart_post = uart.clone();
-    server.fn_handler::<anyhow::Error, _>("/clock", Method::Post, move |mut request| {
-        log::info!("Setting clock");
-
-        // Check content type
-        if !request
-            .content_type()
-            .is_some_and(|content_type| content_type == CONTENT_TYPE_JSON)
-        {
-            log::warn!("Content type not supported");
-            request
-                .into_status_response(STATUS_CODE_UNSUPPORTED_MEDIA_TYPE)?
-                .write(b"Content type not supported")?;
-            return Ok(());
-        }
+    let handler = error_handling_wrapper(
+        "/clock",
+        authorized(auth, Scope::Config, move |request| {
+            log::info!("Setting clock");
+            let _watchdog = Watchdog::subscribe()?;
+            require_content_type(request, CONTENT_TYPE_JSON)?;
+            let clock = read_json_body::<Clock>(request, CLOCK_BODY_MAX_SIZE)?;
 
-        let clock = match read_json_body::<Clock>(&mut request) {
-            Ok(clock) => clock,
-            Err(err) => {
-                log::error!(
-                    "Bad request: {}\n{}",
-                    err.to_string(),
-                    err.root_cause().to_string()
-                );
-                request
-                    .into_status_response(STATUS_CODE_BAD_REQUEST)?
-                    .write_all(err.to_string().as_bytes())?;
-                return Ok(());
-            }
-        };
+            let command = RealTimeClock::default()
+                .year(clock.year)
+                .month(clock.month)
+                .day(clock.day)
+                .hour(clock.hour)
+                .minute(clock.minute)
+                .second(clock.second)
+                .command();
+            write_panel(&uart_post, &command)?;
+            Ok(Response::ok())
+        }),
+    );
+    routes.add("/clock", Method::Post, handler);
+}
 
-        let command = RealTimeClock::default()
-            .year(clock.year)
-            .month(clock.month)
-            .day(clock.day)
-            .hour(clock.hour)
-            .minute(clock.minute)
-            .second(clock.second)
-            .command();
-
-        // Lock the UART to get exclusive access
-        // The lock is automatically released when uart_guard goes out of scope
-        let uart = uart_post
-            .lock()
-            .map_err(|e| anyhow::anyhow!("Failed to lock UART: {:?}", e))?;
-
-        // Write to the UART
-        uart.write(&command)?;
-
-        // Respond to the HTTP request
-        request.into_ok_response()?.write(&[])?;
-        Ok(())
-    })?;
-    Ok(())
+/// Successful answer of a handler, failures are answered by `error_handling_wrapper`.
+pub enum Response {
+    /// Status without a body
+    Status(u16),
+    Body {
+        content_type: &'static str,
+        body: Cow<'static, [u8]>,
+    },
+}
+
+impl Response {
+    fn ok() -> Self {
+        Response::Status(200)
+    }
+
+    fn json<T: Serialize + ?Sized>(value: &T) -> Result<Self, CustomError> {
+        Ok(Response::Body {
+            content_type: CONTENT_TYPE_JSON,
+            body: Cow::Owned(serde_json::to_vec(value).map_err(anyhow::Error::from)?),
+        })
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum CustomError {
     #[error("Content type \"{received}\" not accepted. Try \"{expected}\" instead.")]
     InvalidContentType {
-        expected: String<32>,
-        received: String<32>,
+        expected: &'static str,
+        received: std::string::String,
+    },
+    #[error("Invalid {field}: {message}")]
+    Validation {
+        field: std::string::String,
+        message: std::string::String,
     },
-    #[error("Parsing Error")]
-    Parsing(#[from] serde_json::Error),
+    #[error("Invalid JSON: {message}")]
+    Parsing {
+        /// Path of the offending field like `targets.wifi`, `None` for the document itself
+        field: Option<std::string::String>,
+        /// Includes line and column
+        message: std::string::String,
+    },
+    #[error("Content-Length header required")]
+    LengthRequired,
+    #[error("Body exceeds {limit} bytes")]
+    TooLarge { limit: usize },
+    #[error("Body ended after {received} of {expected} bytes")]
+    IncompleteBody { received: usize, expected: usize },
+    #[error("{0}")]
+    NotFound(std::string::String),
+    #[error("{0}")]
+    Conflict(std::string::String),
+    #[error("{0}")]
+    Unavailable(&'static str),
+    #[error("Too many requests, retry after {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Panel error: {0}")]
+    Panel(anyhow::Error),
+    #[error(transparent)]
+    Ota(#[from] OtaError),
+    #[error(transparent)]
+    Upload(UploadError),
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[error("IO Error: {0:?}")]
+    Io(#[from] EspIOError),
+    #[error("ESP-IDF Error: {0}")]
+    Esp(#[from] EspError),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl From<UploadError> for CustomError {
+    fn from(err: UploadError) -> Self {
+        match err {
+            UploadError::Ota(err) => CustomError::Ota(err),
+            err => CustomError::Upload(err),
+        }
+    }
+}
 
-    #[error("Unknown Error")]
-    Unknown,
+impl From<RevokeError> for CustomError {
+    fn from(err: RevokeError) -> Self {
+        match err {
+            RevokeError::Auth(err) => CustomError::Auth(err),
+            RevokeError::NotFound(_) => CustomError::NotFound(err.to_string()),
+            RevokeError::LastFullAccess => CustomError::Conflict(err.to_string()),
+            RevokeError::Storage(err) => CustomError::Internal(err),
+        }
+    }
 }
 
-fn add_text_handler(server: &mut EspHttpServer<'static>, uart: Arc<Mutex<Uart>>) -> Result<()> {
-    server.fn_handler::<CustomError, _>(
+impl From<LevelsError> for CustomError {
+    fn from(err: LevelsError) -> Self {
+        match err {
+            LevelsError::TooLarge => CustomError::Validation {
+                field: "targets".into(),
+                message: err.to_string(),
+            },
+            LevelsError::Esp(err) => CustomError::Esp(err),
+        }
+    }
+}
+
+impl CustomError {
+    pub fn status(&self) -> u16 {
+        match self {
+            CustomError::InvalidContentType { .. } => STATUS_CODE_UNSUPPORTED_MEDIA_TYPE,
+            CustomError::Validation { .. }
+            | CustomError::Parsing { .. }
+            | CustomError::IncompleteBody { .. } => STATUS_CODE_BAD_REQUEST,
+            CustomError::LengthRequired => STATUS_CODE_LENGTH_REQUIRED,
+            CustomError::TooLarge { .. } => STATUS_CODE_REQUEST_ENTITY_TO_LARGE,
+            CustomError::NotFound(_) => STATUS_CODE_NOT_FOUND,
+            CustomError::Conflict(_) => STATUS_CODE_CONFLICT,
+            CustomError::Unavailable(_) => STATUS_CODE_SERVICE_UNAVAILABLE,
+            CustomError::RateLimited { .. } => STATUS_CODE_TOO_MANY_REQUESTS,
+            CustomError::Auth(AuthError::Unauthenticated) => STATUS_CODE_UNAUTHORIZED,
+            CustomError::Auth(AuthError::MissingScope(_)) => STATUS_CODE_FORBIDDEN,
+            CustomError::Panel(_) => STATUS_CODE_BAD_GATEWAY,
+            CustomError::Ota(err) => match err {
+                OtaError::TooLarge => STATUS_CODE_REQUEST_ENTITY_TO_LARGE,
+                OtaError::Downgrade { .. } | OtaError::InProgress => STATUS_CODE_CONFLICT,
+                OtaError::InvalidSignature => STATUS_CODE_FORBIDDEN,
+                OtaError::Esp(_) => STATUS_CODE_INTERNAL_SERVER_ERROR,
+                OtaError::InvalidImage
+                | OtaError::ChipMismatch { .. }
+                | OtaError::ProjectMismatch { .. }
+                | OtaError::DigestMismatch { .. }
+                | OtaError::SignatureRequired => STATUS_CODE_BAD_REQUEST,
+            },
+            CustomError::Upload(err) => match err {
+                UploadError::Timeout { .. } => STATUS_CODE_REQUEST_TIMEOUT,
+                UploadError::Incomplete { .. } | UploadError::Read(_) => STATUS_CODE_BAD_REQUEST,
+                UploadError::Ota(_) => STATUS_CODE_INTERNAL_SERVER_ERROR,
+            },
+            CustomError::Tls(TlsError::Storage(_)) => STATUS_CODE_INTERNAL_SERVER_ERROR,
+            CustomError::Tls(_) => STATUS_CODE_BAD_REQUEST,
+            CustomError::Io(_) | CustomError::Esp(_) | CustomError::Internal(_) => {
+                STATUS_CODE_INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Machine readable code of the error envelope.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CustomError::InvalidContentType { .. } => "invalid_content_type",
+            CustomError::Validation { .. } => "validation_failed",
+            CustomError::Parsing { .. } => "invalid_json",
+            CustomError::IncompleteBody { .. } => "incomplete_body",
+            CustomError::LengthRequired => "length_required",
+            CustomError::TooLarge { .. } => "payload_too_large",
+            CustomError::NotFound(_) => "not_found",
+            CustomError::Conflict(_) => "conflict",
+            CustomError::Unavailable(_) => "unavailable",
+            CustomError::RateLimited { .. } => "rate_limited",
+            CustomError::Auth(AuthError::Unauthenticated) => "unauthenticated",
+            CustomError::Auth(AuthError::MissingScope(_)) => "missing_scope",
+            CustomError::Panel(_) => "panel_error",
+            CustomError::Ota(err) => match err {
+                OtaError::TooLarge => "payload_too_large",
+                OtaError::InvalidImage => "invalid_image",
+                OtaError::ChipMismatch { .. } => "chip_mismatch",
+                OtaError::ProjectMismatch { .. } => "project_mismatch",
+                OtaError::Downgrade { .. } => "downgrade",
+                OtaError::DigestMismatch { .. } => "digest_mismatch",
+                OtaError::SignatureRequired => "signature_required",
+                OtaError::InvalidSignature => "invalid_signature",
+                OtaError::InProgress => "update_in_progress",
+                OtaError::Esp(_) => "flashing_failed",
+            },
+            CustomError::Upload(err) => match err {
+                UploadError::Timeout { .. } => "request_timeout",
+                UploadError::Incomplete { .. } => "incomplete_body",
+                UploadError::Read(_) => "invalid_body",
+                UploadError::Ota(_) => "flashing_failed",
+            },
+            CustomError::Tls(err) => match err {
+                TlsError::MissingCertificate | TlsError::InvalidCertificate => {
+                    "invalid_certificate"
+                }
+                TlsError::MissingPrivateKey | TlsError::InvalidPrivateKey => "invalid_private_key",
+                TlsError::KeyMismatch => "key_mismatch",
+                TlsError::Storage(_) => "storage_failed",
+            },
+            CustomError::Io(_) | CustomError::Esp(_) | CustomError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Request field the error refers to, if any.
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            CustomError::Validation { field, .. } => Some(field),
+            CustomError::Parsing { field, .. } => field.as_deref(),
+            CustomError::Auth(AuthError::MissingScope(_)) => Some("scope"),
+            CustomError::Ota(OtaError::DigestMismatch { .. }) => Some(QUERY_PARAMETER_SHA256),
+            CustomError::Ota(OtaError::SignatureRequired | OtaError::InvalidSignature) => {
+                Some(QUERY_PARAMETER_SIGNATURE)
+            }
+            CustomError::Ota(OtaError::Downgrade { .. }) => Some(QUERY_PARAMETER_FORCE),
+            _ => None,
+        }
+    }
+}
+
+fn add_text_handler(routes: &mut Routes, uart: Arc<Mutex<Uart>>, auth: Auth) {
+    let uart_post = uart.clone();
+    let handler = error_handling_wrapper(
         "/text",
-        Method::Post,
-        error_handling_wrapper(|mut request| {
+        authorized(auth.clone(), Scope::Content, move |request| {
+            log::info!("Setting Panel text");
+            let _watchdog = Watchdog::subscribe()?;
+            let formatted_text = read_formatted_text(request)?;
+            show_text(&uart_post, &formatted_text)
+        }),
+    );
+    routes.add("/text", Method::Post, handler);
+
+    // Changes what the panel shows despite being a GET, e.g. `/text?msg=Hello`
+    let handler = error_handling_wrapper(
+        "/text",
+        authorized(auth, Scope::Content, move |request| {
             log::info!("Setting Panel text");
+            let _watchdog = Watchdog::subscribe()?;
+            let message =
+                query_parameter(request.uri(), QUERY_PARAMETER_MESSAGE).ok_or_else(|| {
+                    CustomError::Validation {
+                        field: QUERY_PARAMETER_MESSAGE.into(),
+                        message: "Query parameter required".into(),
+                    }
+                })?;
+            let formatted_text = plain_text(&decode_parameter(message, QUERY_PARAMETER_MESSAGE)?)?;
+            show_text(&uart, &formatted_text)
+        }),
+    );
+    routes.add("/text", Method::Get, handler);
+}
+
+/// Text from the `msg` query parameter, or else from a JSON, plain text or form body.
+fn read_formatted_text(
+    request: &mut Request<&mut EspHttpConnection<'_>>,
+) -> Result<FormattedText, CustomError> {
+    if let Some(message) = query_parameter(request.uri(), QUERY_PARAMETER_MESSAGE) {
+        return plain_text(&decode_parameter(message, QUERY_PARAMETER_MESSAGE)?);
+    }
+
+    let content_type = request.content_type().unwrap_or_default();
+    if is_media_type(content_type, CONTENT_TYPE_JSON) {
+        read_json_body::<FormattedText>(request, TEXT_BODY_MAX_SIZE)
+    } else if is_media_type(content_type, CONTENT_TYPE_TEXT) {
+        let body = read_body(request, TEXT_BODY_MAX_SIZE)?;
+        let text = utf8_text(&body)?;
+        plain_text(text.trim_end_matches(['\r', '\n']))
+    } else if is_media_type(content_type, CONTENT_TYPE_FORM) {
+        let body = read_body(request, TEXT_BODY_MAX_SIZE)?;
+        let form = utf8_text(&body)?.trim_end();
+        // `curl -d 'Hello'` sends the bare message without a field name
+        let message = form_value(form, QUERY_PARAMETER_MESSAGE).unwrap_or(form);
+        plain_text(&decode_parameter(message, QUERY_PARAMETER_MESSAGE)?)
+    } else {
+        Err(CustomError::InvalidContentType {
+            expected: CONTENT_TYPE_JSON,
+            received: content_type.to_string(),
+        })
+    }
+}
 
-            // Check content type
+fn utf8_text(body: &[u8]) -> Result<&str, CustomError> {
+    core::str::from_utf8(body).map_err(|_| CustomError::Validation {
+        field: "text".into(),
+        message: "Text must be UTF-8".into(),
+    })
+}
+
+/// Text with the default effects.
+fn plain_text(text: &str) -> Result<FormattedText, CustomError> {
+    let text = String::try_from(text).map_err(|_| CustomError::Validation {
+        field: "text".into(),
+        message: format!("Text must not exceed {TEXT_MAX_LENGTH} bytes"),
+    })?;
+    Ok(FormattedText {
+        text,
+        leading: Leading::default(),
+        lagging: Lagging::default(),
+        waiting_mode_and_speed: WaitingModeAndSpeed::default(),
+    })
+}
+
+fn show_text(uart: &Mutex<Uart>, formatted_text: &FormattedText) -> Result<Response, CustomError> {
+    let command = PageContent::default()
+        .leading(formatted_text.leading)
+        .lagging(formatted_text.lagging)
+        .waiting_mode_and_speed(formatted_text.waiting_mode_and_speed)
+        .message(&formatted_text.text)
+        .command();
+    write_panel(uart, &command)?;
+    Ok(Response::ok())
+}
+
+fn add_update_handler(routes: &mut Routes, uart: Arc<Mutex<Uart>>, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/update",
+        authorized(auth, Scope::Firmware, move |request| {
+            log::info!("Starting updater");
+            metrics::ota_attempted();
+            let watchdog = Watchdog::subscribe()?;
+
+            // Browsers upload files from a form as multipart body
             let content_type = request.content_type().unwrap_or_default();
-            if content_type == CONTENT_TYPE_JSON {
+            let boundary = if is_media_type(content_type, CONTENT_TYPE_OCTET_STEAM) {
+                None
+            } else if let Some(boundary) = http_body::multipart_boundary(content_type) {
+                Some(boundary.to_string())
+            } else {
                 return Err(CustomError::InvalidContentType {
-                    expected: String::from_str(CONTENT_TYPE_JSON)
-                        .map_err(|_| CustomError::Unknown)?,
-                    received: String::from_str("").map_err(|_| CustomError::Unknown)?,
+                    expected: CONTENT_TYPE_OCTET_STEAM,
+                    received: content_type.to_string(),
                 });
+            };
+
+            let chunked = request
+                .header(HEADER_TRANSFER_ENCODING)
+                .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+            let content_length = match request.content_len() {
+                _ if chunked => None,
+                Some(length) => Some(length as usize),
+                None => return Err(CustomError::LengthRequired),
+            };
+            let compressed = request
+                .header(HEADER_CONTENT_ENCODING)
+                .is_some_and(|encoding| encoding.eq_ignore_ascii_case("gzip"));
+            // The size of a multipart file is only known once its closing boundary arrives
+            // and the size of a compressed image only once it is decompressed
+            let firmware_size = content_length.filter(|_| boundary.is_none() && !compressed);
+
+            if firmware_size.is_some_and(|size| size > ota::PARTITION_SIZE) {
+                return Err(OtaError::TooLarge.into());
             }
-            let formatted_text = read_json_body::<FormattedText>(&mut request)?;
 
-            let command = PageContent::default()
-                .leading(formatted_text.leading)
-                .lagging(formatted_text.lagging)
-                .waiting_mode_and_speed(formatted_text.waiting_mode_and_speed)
-                .message(&formatted_text.text)
-                .command();
+            // The expected digest is optional and can be passed as header or query parameter
+            let expected_digest = request
+                .header(HEADER_FIRMWARE_SHA256)
+                .or_else(|| query_parameter(request.uri(), QUERY_PARAMETER_SHA256))
+                .map(|digest| {
+                    hex::decode::<{ ota::DIGEST_LENGTH }>(digest).ok_or_else(|| {
+                        CustomError::Validation {
+                            field: QUERY_PARAMETER_SHA256.into(),
+                            message: "SHA-256 digest must be 64 hex characters".into(),
+                        }
+                    })
+                })
+                .transpose()?;
 
-            // Lock the UART to get exclusive access
-            let uart = uart.lock().map_err(|err| CustomError::Unknown)?;
+            // Hex encoded Ed25519 signature over the SHA-256 digest of the firmware
+            let signature = request
+                .header(HEADER_FIRMWARE_SIGNATURE)
+                .or_else(|| query_parameter(request.uri(), QUERY_PARAMETER_SIGNATURE))
+                .map(|signature| {
+                    hex::decode::<{ ota::SIGNATURE_LENGTH }>(signature).ok_or_else(|| {
+                        CustomError::Validation {
+                            field: QUERY_PARAMETER_SIGNATURE.into(),
+                            message: "Firmware signature must be 128 hex characters".into(),
+                        }
+                    })
+                })
+                .transpose()?;
+            if signature.is_none() {
+                return Err(OtaError::SignatureRequired.into());
+            }
 
-            // Write to the UART and handle errors
-            uart.write(&command).context("Failed to write to uart")?;
+            let lock = ota::lock_update()?;
+            let mut ota = EspOta::new()?;
+            // Older firmware is only accepted with `force=true`
+            let force = query_parameter(request.uri(), QUERY_PARAMETER_FORCE) == Some("true");
+            let updater = Updater::new(&mut ota, lock, firmware_size)?.allow_downgrade(force);
+            // Progress is shown on the panel unless disabled with `display=false`
+            let display = query_parameter(request.uri(), QUERY_PARAMETER_DISPLAY) != Some("false");
+            let upload = Upload {
+                expected_size: firmware_size,
+                expected_digest,
+                signature,
+                panel: display.then(|| Arc::clone(&uart)),
+            };
 
-            // Respond to the HTTP request
-            request.into_ok_response()?.write(&[])?;
-            Ok(Vec::new())
+            {
+                let mut raw: Box<dyn Read<Error = BodyError> + '_> = if chunked {
+                    Box::new(ChunkedBody::new(&mut *request))
+                } else {
+                    Box::new(RawBody(&mut *request))
+                };
+                let mut multipart;
+                let body: &mut dyn Read<Error = BodyError> = match &boundary {
+                    Some(boundary) => {
+                        multipart = MultipartBody::new(&mut *raw, boundary);
+                        &mut multipart
+                    }
+                    None => &mut *raw,
+                };
+                // Forms cannot set a content encoding, so gzip is detected inside multipart files
+                if compressed || boundary.is_some() {
+                    upload.run(&mut GzipBody::new(body), updater, &watchdog)?;
+                } else {
+                    upload.run(body, updater, &watchdog)?;
+                }
+                // The firmware is already in place, so the response does not depend on this
+                if let Err(err) = http_body::drain(body) {
+                    log::warn!("Discarding the rest of the upload failed: {err}");
+                }
+            }
+
+            // Only reached if the upload completed, so the new firmware is booted
+            upload.display("REBOOTING");
+            ota::schedule_reboot(OTA_REBOOT_DELAY)?;
+            Ok(Response::ok())
         }),
-    )?;
-    Ok(())
+    );
+    routes.add("/update", Method::Post, handler);
 }
 
-fn add_update_handler(server: &mut EspHttpServer<'static>) -> Result<()> {
-    server.fn_handler::<anyhow::Error, _>("/update", Method::Post, |mut request| {
-        log::info!("Starting updater");
+fn add_update_progress_handler(routes: &mut Routes, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/update/progress",
+        authorized(auth, Scope::Read, |_request| {
+            Response::json(&ota::progress())
+        }),
+    );
+    routes.add("/update/progress", Method::Get, handler);
+}
 
-        if !request
-            .content_type()
-            .is_some_and(|content_type| content_type == CONTENT_TYPE_OCTET_STEAM)
-        {
-            log::warn!("Content type not supported");
-            request
-                .into_status_response(STATUS_CODE_UNSUPPORTED_MEDIA_TYPE)?
-                .write(b"Content type not supported")?;
-            return Ok(());
-        }
+fn add_update_slots_handler(routes: &mut Routes, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/update/slots",
+        authorized(auth, Scope::Read, |_request| {
+            log::info!("Sending OTA slots");
+            Response::json(&ota::slots()?)
+        }),
+    );
+    routes.add("/update/slots", Method::Get, handler);
+}
 
-        let firmware_size = match request.content_len() {
-            None => {
-                request.into_status_response(STATUS_CODE_LENGTH_REQUIRED)?;
-                return Ok(());
+fn add_update_slots_switch_handler(routes: &mut Routes, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/update/slots/switch",
+        authorized(auth, Scope::Firmware, |_request| {
+            log::info!("Switching boot slot");
+            match ota::switch_boot_slot() {
+                Ok(_) => {
+                    ota::schedule_reboot(OTA_REBOOT_DELAY)?;
+                    Ok(Response::ok())
+                }
+                Err(OtaError::InvalidImage) => Err(CustomError::Conflict(
+                    "The other slot holds no valid firmware".into(),
+                )),
+                Err(err) => Err(err.into()),
             }
-            Some(size) => size as usize,
-        };
+        }),
+    );
+    routes.add("/update/slots/switch", Method::Post, handler);
+}
 
-        if firmware_size > OTA_PARTITION_SIZE {
-            request.into_status_response(STATUS_CODE_REQUEST_ENTITY_TO_LARGE)?;
-            return Ok(());
-        }
+fn add_update_check_handler(routes: &mut Routes, update_trigger: UpdateTrigger, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/update/check",
+        authorized(auth, Scope::Firmware, move |_request| {
+            log::info!("Triggering update check");
+            if !update_trigger.trigger() {
+                return Err(CustomError::Unavailable("Update client is not running"));
+            }
+            Ok(Response::Status(STATUS_CODE_ACCEPTED))
+        }),
+    );
+    routes.add("/update/check", Method::Post, handler);
+}
 
-        let mut ota = EspOta::new()?;
-        let running_slot = ota.get_running_slot()?;
-        let update_slot = ota.get_update_slot()?;
-        log::info!(
-            "Current slot: {} - {}",
-            running_slot.label,
-            running_slot.firmware.unwrap().version
-        );
-        log::info!("Update slot: {}", update_slot.label);
-
-        let mut ota_updater = ota.initiate_update()?;
-        let mut buffer = [0; OTA_CHNUNK_SIZE];
-        let mut total_bytes_read: usize = 0;
-
-        log::info!("Start uploading. Expected {firmware_size} bytes");
-        loop {
-            let bytes_read = request.read(&mut buffer)?;
-            total_bytes_read += bytes_read;
-            log::info!("Read {total_bytes_read}/{firmware_size} bytes from firmware");
-
-            if bytes_read > 0 {
-                if let Err(err) = ota_updater.write(&buffer[..bytes_read]) {
-                    log::error!("Error: {err}");
-                    ota_updater.abort()?;
-                    break;
-                }
+fn add_status_handler(routes: &mut Routes, hostname: String<HOSTNAME_MAX_LENGTH>) {
+    // Public but still rate limited
+    let handler = error_handling_wrapper(
+        "/status",
+        rate_limited(move |_request| {
+            log::info!("Sending Status information");
+            let ota = EspOta::new()?;
+            let running_slot = ota.get_running_slot()?;
+
+            let diagnostics = diagnostics::get();
+            let status = Status {
+                hostname: hostname.clone(),
+                version: running_slot.firmware.unwrap().version,
+                slot: running_slot.label,
+                slot_state: ota::slot_state_name(&running_slot.state),
+                reset_reason: diagnostics.map_or("unknown", |diagnostics| diagnostics.reset_reason),
+                boot_count: diagnostics.map_or(0, |diagnostics| diagnostics.boot_count),
+                last_panic: diagnostics.and_then(|diagnostics| diagnostics.last_panic.as_deref()),
+            };
+            Response::json(&status)
+        }),
+    );
+    routes.add("/status", Method::Get, handler);
+}
+
+fn add_metrics_handler(routes: &mut Routes, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/metrics",
+        authorized(auth, Scope::Read, |_request| {
+            Ok(Response::Body {
+                content_type: CONTENT_TYPE_METRICS,
+                body: Cow::Owned(metrics::render().into_bytes()),
+            })
+        }),
+    );
+    routes.add("/metrics", Method::Get, handler);
+}
+
+fn add_diagnostics_handler(routes: &mut Routes, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/diagnostics",
+        authorized(auth.clone(), Scope::Read, |_request| {
+            log::info!("Sending diagnostics");
+            let diagnostics =
+                diagnostics::get().ok_or_else(|| anyhow::anyhow!("Diagnostics not collected"))?;
+            Response::json(diagnostics)
+        }),
+    );
+    routes.add("/diagnostics", Method::Get, handler);
+
+    let handler = error_handling_wrapper(
+        "/diagnostics/memory",
+        authorized(auth, Scope::Read, |_request| {
+            log::info!("Sending memory diagnostics");
+            Response::json(&diagnostics::memory())
+        }),
+    );
+    routes.add("/diagnostics/memory", Method::Get, handler);
+}
+
+fn add_log_level_handler(routes: &mut Routes, nvs_partition: EspDefaultNvsPartition, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/log-level",
+        authorized(auth.clone(), Scope::Read, |_request| {
+            log::info!("Sending log levels");
+            Response::json(&logger::levels())
+        }),
+    );
+    routes.add("/log-level", Method::Get, handler);
+
+    let handler = error_handling_wrapper(
+        "/log-level",
+        authorized(auth, Scope::Config, move |request| {
+            log::info!("Setting log levels");
+            require_content_type(request, CONTENT_TYPE_JSON)?;
+            let update = read_json_body::<LogLevelUpdate>(request, LOG_LEVEL_BODY_MAX_SIZE)?;
+
+            logger::set_levels(&update.levels, &update.clear)?;
+            if update.persist {
+                logger::store_levels(nvs_partition.clone())?;
             }
+            Response::json(&logger::levels())
+        }),
+    );
+    routes.add("/log-level", Method::Post, handler);
+}
 
-            if total_bytes_read >= firmware_size {
-                log::info!("Update finished");
-                ota_updater.complete()?;
-                break;
+fn add_token_handlers(routes: &mut Routes, auth: Auth) {
+    let tokens = auth.clone();
+    let handler = error_handling_wrapper(
+        "/tokens",
+        authorized(auth.clone(), Scope::Config, move |_request| {
+            log::info!("Sending API tokens");
+            Response::json(&tokens.tokens())
+        }),
+    );
+    routes.add("/tokens", Method::Get, handler);
+
+    let tokens = auth.clone();
+    let handler = error_handling_wrapper(
+        "/tokens",
+        authorized_with_scopes(auth.clone(), Scope::Config, move |request, scopes| {
+            log::info!("Creating API token");
+            require_content_type(request, CONTENT_TYPE_JSON)?;
+            let creation = read_json_body::<TokenCreation>(request, TOKEN_BODY_MAX_SIZE)?;
+
+            // Tokens can not grant more than the token creating them
+            if let Some(scope) = creation.scopes.iter().find(|scope| !scopes.contains(scope)) {
+                return Err(AuthError::MissingScope(*scope).into());
             }
-        }
 
-        let reboot_timer = EspTimerService::new()?;
-        let reboot_timer = reboot_timer.timer(move || {
-            log::info!("Rebooting");
-            restart();
-        })?;
-        log::info!("Schedule reboot in 5 seconds...");
-        request.into_ok_response()?;
-        reboot_timer.after(Duration::from_secs(5))?;
-        std::mem::forget(reboot_timer);
-        Ok(())
-    })?;
-    Ok(())
+            let (token, info) = tokens.create(creation.scopes)?;
+            Response::json(&CreatedToken { token, info })
+        }),
+    );
+    routes.add("/tokens", Method::Post, handler);
+
+    let tokens = auth.clone();
+    let handler = error_handling_wrapper(
+        "/tokens/revoke",
+        authorized_with_scopes(auth.clone(), Scope::Config, move |request, scopes| {
+            log::info!("Revoking API token");
+            require_content_type(request, CONTENT_TYPE_JSON)?;
+            let revocation = read_json_body::<TokenRevocation>(request, TOKEN_BODY_MAX_SIZE)?;
+            tokens.revoke(&revocation.id, scopes)?;
+            Ok(Response::ok())
+        }),
+    );
+    routes.add("/tokens/revoke", Method::Post, handler);
+
+    let tokens = auth.clone();
+    let handler = error_handling_wrapper(
+        "/tokens/*",
+        authorized_with_scopes(auth, Scope::Config, move |request, scopes| {
+            let id = path_parameter(request.uri()).unwrap_or_default();
+            log::info!("Revoking API token {id}");
+            tokens.revoke(id, scopes)?;
+            Ok(Response::ok())
+        }),
+    );
+    routes.add_versioned("/tokens/*", Method::Delete, handler);
 }
 
-fn add_status_handler(server: &mut EspHttpServer<'static>, hostname: String<30>) -> Result<()> {
-    server.fn_handler::<anyhow::Error, _>("/status", Method::Get, move |request| {
-        log::info!("Sending Status information");
-        let ota = EspOta::new()?;
-        let running_slot = ota.get_running_slot()?;
+fn add_rate_limit_handler(routes: &mut Routes, nvs_partition: EspDefaultNvsPartition, auth: Auth) {
+    let handler = error_handling_wrapper(
+        "/rate-limit",
+        authorized(auth.clone(), Scope::Read, |_request| {
+            log::info!("Sending rate limits");
+            Response::json(&rate_limit::limits())
+        }),
+    );
+    routes.add("/rate-limit", Method::Get, handler);
 
-        let status = Status {
-            hostname: hostname.clone(),
-            version: running_slot.firmware.unwrap().version,
-        };
+    let handler = error_handling_wrapper(
+        "/rate-limit",
+        authorized(auth, Scope::Config, move |request| {
+            log::info!("Setting rate limits");
+            require_content_type(request, CONTENT_TYPE_JSON)?;
+            let limits = read_json_body::<RateLimits>(request, RATE_LIMIT_BODY_MAX_SIZE)?;
 
-        let status = serde_json::to_string(&status)?;
-        request.into_ok_response()?.write_all(&status.as_bytes())?;
-        Ok(())
-    })?;
+            rate_limit::set_limits(nvs_partition.clone(), limits)?;
+            Response::json(&rate_limit::limits())
+        }),
+    );
+    routes.add("/rate-limit", Method::Post, handler);
+}
+
+fn add_tls_certificate_handler(
+    routes: &mut Routes,
+    nvs_partition: EspDefaultNvsPartition,
+    auth: Auth,
+) {
+    let handler = error_handling_wrapper(
+        "/tls/certificate",
+        authorized(auth, Scope::Config, move |request| {
+            log::info!("Storing TLS certificate");
+            require_content_type(request, CONTENT_TYPE_PEM)?;
+
+            // Certificate chain followed by the private key
+            let pem = read_body(request, TLS_CERTIFICATE_MAX_SIZE)?;
+            let pem = core::str::from_utf8(&pem).map_err(|_| TlsError::InvalidCertificate)?;
+            tls::store(nvs_partition.clone(), pem)?;
+            // The server only loads the certificate on startup
+            ota::schedule_reboot(OTA_REBOOT_DELAY)?;
+            Ok(Response::ok())
+        }),
+    );
+    routes.add("/tls/certificate", Method::Post, handler);
+}
+
+/// Sends a command to the panel while holding the UART exclusively.
+fn write_panel(uart: &Mutex<Uart>, command: &str) -> Result<(), CustomError> {
+    let uart = uart
+        .lock()
+        .map_err(|err| CustomError::Panel(anyhow::anyhow!("Failed to lock UART: {err:?}")))?;
+    uart.write(command).map_err(CustomError::Panel)
+}
+
+fn require_content_type(
+    request: &Request<&mut EspHttpConnection<'_>>,
+    expected: &'static str,
+) -> Result<(), CustomError> {
+    let content_type = request.content_type().unwrap_or_default();
+    if !is_media_type(content_type, expected) {
+        return Err(CustomError::InvalidContentType {
+            expected,
+            received: content_type.to_string(),
+        });
+    }
     Ok(())
 }
 
+/// Compares the media type case-insensitively, ignoring parameters like the charset.
+fn is_media_type(content_type: &str, media_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .eq_ignore_ascii_case(media_type)
+}
+
+/// Last path segment of a wildcard route like `/tokens/*`.
+fn path_parameter(uri: &str) -> Option<&str> {
+    let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+    path.rsplit_once('/')
+        .map(|(_, parameter)| parameter)
+        .filter(|parameter| !parameter.is_empty())
+}
+
+fn query_parameter<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    form_value(query, name)
+}
+
+/// Still URL encoded value of a field of a query string or form body.
+fn form_value<'a>(form: &'a str, name: &str) -> Option<&'a str> {
+    form.split('&')
+        .filter_map(|parameter| parameter.split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+/// Decodes a URL encoded value, `+` stands for a space.
+fn url_decode(value: &str) -> Option<std::string::String> {
+    let mut bytes = std::vec::Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let digits = [input.next()?, input.next()?];
+                let [decoded] = hex::decode::<1>(core::str::from_utf8(&digits).ok()?)?;
+                bytes.push(decoded);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    std::string::String::from_utf8(bytes).ok()
+}
+
+fn decode_parameter(value: &str, name: &str) -> Result<std::string::String, CustomError> {
+    url_decode(value).ok_or_else(|| CustomError::Validation {
+        field: name.into(),
+        message: "Invalid URL encoding".into(),
+    })
+}
+
+/// Reads and parses a JSON body of at most `limit` bytes.
 fn read_json_body<T: DeserializeOwned>(
     request: &mut Request<&mut EspHttpConnection<'_>>,
+    limit: usize,
 ) -> Result<T, CustomError> {
-    // Read request body
-    let mut body = Vec::<u8, 256>::new();
-    let mut buffer = [0u8; 128];
-    loop {
-        match request.read(&mut buffer) {
-            Ok(0) => break, // No more data to read
-            Ok(n) => body.extend_from_slice(&buffer[..n]).unwrap(),
-            Err(e) => {
-                return Err(CustomError::Unknown);
-            }
+    let body = read_body(request, limit)?;
+    let mut deserializer = serde_json::Deserializer::from_slice(&body);
+    let json_body = serde_path_to_error::deserialize(&mut deserializer).map_err(|err| {
+        let path = err.path().to_string();
+        CustomError::Parsing {
+            // The root is reported as "."
+            field: (path != ".").then_some(path),
+            message: err.into_inner().to_string(),
         }
+    })?;
+    // Reject trailing characters after the value
+    deserializer.end().map_err(|err| CustomError::Parsing {
+        field: None,
+        message: err.to_string(),
+    })?;
+    Ok(json_body)
+}
+
+/// Reads a body of at most `limit` bytes.
+/// The size has to be announced by Content-Length so oversized bodies are rejected before reading.
+fn read_body(
+    request: &mut Request<&mut EspHttpConnection<'_>>,
+    limit: usize,
+) -> Result<std::vec::Vec<u8>, CustomError> {
+    let expected = request.content_len().ok_or(CustomError::LengthRequired)? as usize;
+    if expected > limit {
+        return Err(CustomError::TooLarge { limit });
     }
 
-    // Parse the JSON body
-    let json_body = serde_json::from_slice::<T>(&body)?;
-    Ok(json_body)
+    let mut body = vec![0u8; expected];
+    let mut received = 0;
+    while received < expected {
+        match request.read(&mut body[received..])? {
+            0 => return Err(CustomError::IncompleteBody { received, expected }),
+            n => received += n,
+        }
+    }
+    Ok(body)
 }
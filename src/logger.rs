@@ -0,0 +1,228 @@
+use crate::syslog::{self, Syslog};
+use anyhow::Result;
+use esp_idf_svc::{
+    log::EspLogger,
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    sys::{EspError, CONFIG_LOG_DEFAULT_LEVEL},
+};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Mutex};
+use thiserror::Error;
+
+const NVS_NAMESPACE: &str = "logger";
+const NVS_KEY_LEVELS: &str = "levels";
+const NVS_LEVELS_MAX_SIZE: usize = 512;
+// ESP-IDF tag matching every target without an explicit level
+const WILDCARD_TARGET: &str = "*";
+
+static LOGGER: Logger = Logger::new();
+static LEVELS: Mutex<LogLevels> = Mutex::new(LogLevels {
+    level: None,
+    targets: BTreeMap::new(),
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Log levels changed at runtime. Targets are Rust module paths or ESP-IDF component tags.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogLevels {
+    /// Global level, `None` if the build default is used
+    pub level: Option<LogLevel>,
+    #[serde(default)]
+    pub targets: BTreeMap<String, LogLevel>,
+}
+
+#[derive(Error, Debug)]
+pub enum LevelsError {
+    #[error("Log levels must not exceed {NVS_LEVELS_MAX_SIZE} bytes as JSON to be storable")]
+    TooLarge,
+    #[error(transparent)]
+    Esp(#[from] EspError),
+}
+
+/// Logs to the ESP-IDF console and optionally forwards records to a remote syslog collector.
+struct Logger {
+    esp_logger: EspLogger,
+    syslog: Mutex<Option<Syslog>>,
+}
+
+impl Logger {
+    const fn new() -> Self {
+        Self {
+            esp_logger: EspLogger::new(),
+            syslog: Mutex::new(None),
+        }
+    }
+
+    fn with_syslog(&self, f: impl FnOnce(&mut Syslog)) {
+        if let Some(syslog) = self.syslog.lock().unwrap().as_mut() {
+            f(syslog);
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.esp_logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.esp_logger.log(record);
+
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Skip instead of blocking if a record is logged while the syslog sink is busy
+        if let Ok(mut syslog) = self.syslog.try_lock() {
+            if let Some(syslog) = syslog.as_mut() {
+                syslog.log(record.level(), record.target(), &record.args().to_string());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.esp_logger.flush();
+    }
+}
+
+pub fn init() {
+    if let Some(config) = syslog::Config::from_env() {
+        *LOGGER.syslog.lock().unwrap() = Some(Syslog::new(config));
+    }
+    log::set_logger(&LOGGER)
+        .map(|()| LOGGER.esp_logger.initialize())
+        .unwrap();
+}
+
+pub fn set_hostname(hostname: &str) {
+    LOGGER.with_syslog(|syslog| syslog.set_hostname(hostname));
+}
+
+/// Must be called whenever the network goes up or down so records are buffered in between.
+pub fn set_connected(connected: bool) {
+    LOGGER.with_syslog(|syslog| {
+        if connected {
+            syslog.connect();
+        } else {
+            syslog.disconnect();
+        }
+    });
+}
+
+pub fn levels() -> LogLevels {
+    LEVELS.lock().unwrap().clone()
+}
+
+/// Applies the given levels on top of the current ones.
+/// Cleared targets fall back to the global level.
+/// Levels that could not be stored in NVS are rejected, even if they are not persisted.
+pub fn set_levels(levels: &LogLevels, cleared: &[String]) -> Result<(), LevelsError> {
+    let mut current = LEVELS.lock().unwrap();
+    let mut next = current.clone();
+    for target in cleared {
+        next.targets.remove(target);
+    }
+    if let Some(level) = levels.level {
+        next.level = Some(level);
+    }
+    next.targets.extend(levels.targets.clone());
+    // Serializing a map of strings can not fail, a byte is kept for the terminating NUL
+    if serde_json::to_vec(&next).unwrap_or_default().len() >= NVS_LEVELS_MAX_SIZE {
+        return Err(LevelsError::TooLarge);
+    }
+
+    if let Some(level) = levels.level {
+        log::info!("Set log level to {level:?}");
+        let level_filter = LevelFilter::from(level);
+        // The ESP-IDF level has to be raised as well, otherwise records are still filtered there
+        LOGGER
+            .esp_logger
+            .set_target_level(WILDCARD_TARGET, level_filter)?;
+    }
+
+    // ESP-IDF can not forget the level of a tag, so it is set to the global one instead
+    let global_level = next.level.map_or_else(default_level, LevelFilter::from);
+    for target in cleared
+        .iter()
+        .filter(|target| !next.targets.contains_key(*target))
+    {
+        log::info!("Reset log level of {target}");
+        LOGGER.esp_logger.set_target_level(target, global_level)?;
+    }
+
+    for (target, level) in &levels.targets {
+        log::info!("Set log level of {target} to {level:?}");
+        LOGGER
+            .esp_logger
+            .set_target_level(target, LevelFilter::from(*level))?;
+    }
+    *current = next;
+
+    // The global max level must let through the most verbose target but cannot exceed the build maximum
+    let build_max_level = LOGGER.esp_logger.get_max_level();
+    let max_level = current
+        .targets
+        .values()
+        .copied()
+        .map(LevelFilter::from)
+        .fold(
+            current.level.map_or(build_max_level, LevelFilter::from),
+            LevelFilter::max,
+        );
+    log::set_max_level(max_level.min(build_max_level));
+    Ok(())
+}
+
+/// Level of targets without an explicit one as long as no global level is set.
+fn default_level() -> LevelFilter {
+    match CONFIG_LOG_DEFAULT_LEVEL {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+pub fn store_levels(partition: EspDefaultNvsPartition) -> Result<()> {
+    let levels = serde_json::to_string(&levels())?;
+    let mut nvs = EspNvs::<NvsDefault>::new(partition, NVS_NAMESPACE, true)?;
+    nvs.set_str(NVS_KEY_LEVELS, &levels)?;
+    log::info!("Stored log levels");
+    Ok(())
+}
+
+/// Applies log levels previously stored with [`store_levels`].
+pub fn restore_levels(partition: EspDefaultNvsPartition) -> Result<()> {
+    let nvs = EspNvs::<NvsDefault>::new(partition, NVS_NAMESPACE, true)?;
+    let mut buffer = [0u8; NVS_LEVELS_MAX_SIZE];
+    if let Some(levels) = nvs.get_str(NVS_KEY_LEVELS, &mut buffer)? {
+        log::info!("Restore log levels");
+        set_levels(&serde_json::from_str(levels)?, &[])?;
+    }
+    Ok(())
+}
@@ -1,54 +1,139 @@
+mod auth;
 mod base36;
+mod diagnostics;
+mod firmware;
+mod hex;
+mod http_body;
 mod http_server;
+mod logger;
 mod mdns;
+mod metrics;
+mod openapi;
+mod ota;
+mod rate_limit;
+mod supervisor;
+mod syslog;
+mod tls;
 mod uart;
+mod update_client;
+mod upload;
+mod watchdog;
 mod wifi;
 
 
 use anyhow::{Context, Result};
+use auth::Auth;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{prelude::Peripherals, task::block_on},
-    wifi::WifiEvent,
+    nvs::EspDefaultNvsPartition,
+    timer::EspTaskTimerService,
 };
+use ota::HealthConfirmation;
+use std::time::Duration;
+use supervisor::Supervisor;
+use watchdog::Watchdog;
 use wifi::Wifi;
 
 const SSID: &str = env!("WIFI_SSID");
 const PASSWORD: &str = env!("WIFI_PASS");
+const SUPERVISOR_INTERVAL: Duration = Duration::from_secs(5);
 
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    logger::init();
 
     let peripherals = Peripherals::take()?;
     let event_loop = EspSystemEventLoop::take()?;
+    let nvs_partition = EspDefaultNvsPartition::take()?;
 
-    let mut wifi = Wifi::new(event_loop.clone(), peripherals.modem)?;
-    let hostname = wifi.get_hostname()?;
+    diagnostics::init(nvs_partition.clone()).context("Failed to initialize diagnostics")?;
+    if let Err(err) = logger::restore_levels(nvs_partition.clone()) {
+        log::error!("Failed to restore log levels: {err}");
+    }
+    if let Err(err) = rate_limit::init(nvs_partition.clone()) {
+        log::error!("Failed to restore rate limits: {err}");
+    }
 
-    let _mdns = mdns::init(&hostname).context("Failed to initialize mDNS")?;
-    let uart = uart::Uart::new(
-        peripherals.uart1,
-        peripherals.pins.gpio2,
-        peripherals.pins.gpio3,
-    )?;
+    // Failing anywhere before the confirmation leaves an updated firmware unconfirmed,
+    // which makes the bootloader roll back on the next boot.
+    let mut health_confirmation = HealthConfirmation::new()?;
 
-    uart.init().context("Failed to initialize panel")?;
-    let _http_server = http_server::init(hostname, uart).context("Failed to intialize http server")?;
+    let services = (|| {
+        ota::check_public_key().context("Invalid firmware public key")?;
+        let wifi = Wifi::new(event_loop, peripherals.modem, nvs_partition.clone())?;
+        let hostname = wifi.get_hostname()?;
+        logger::set_hostname(&hostname);
+
+        let mdns = mdns::init(&hostname).context("Failed to initialize mDNS")?;
+        let uart = uart::Uart::new(
+            peripherals.uart1,
+            peripherals.pins.gpio2,
+            peripherals.pins.gpio3,
+        )?;
+
+        uart.init().context("Failed to initialize panel")?;
+        let update_trigger =
+            update_client::start().context("Failed to initialize update client")?;
+        let auth =
+            Auth::init(nvs_partition.clone()).context("Failed to initialize authentication")?;
+        let credentials = tls::init(nvs_partition.clone(), &hostname)
+            .context("Failed to initialize TLS certificate")?;
+        let redirect_server =
+            http_server::init_redirect(&hostname).context("Failed to initialize http redirect")?;
+        let http_server = http_server::init(
+            hostname,
+            uart,
+            nvs_partition.clone(),
+            update_trigger,
+            auth,
+            credentials,
+        )
+        .context("Failed to intialize http server")?;
+        let timer = EspTaskTimerService::new()?.timer_async()?;
+        anyhow::Ok((wifi, mdns, redirect_server, http_server, timer))
+    })();
+    // Do not wait for a reboot that might never come
+    let (mut wifi, _mdns, _redirect_server, _http_server, mut timer) =
+        services.inspect_err(|_| health_confirmation.roll_back())?;
+
+    let mut supervisor = Supervisor::new(nvs_partition);
 
     block_on(async move {
-        wifi.connect(SSID, PASSWORD).await.unwrap();
-        let mut wifi_subscription = event_loop.subscribe_async::<WifiEvent>().unwrap();
+        let watchdog = Watchdog::subscribe().unwrap();
+        wifi.start(SSID, PASSWORD).await.unwrap();
 
+        let mut connected = false;
+        let mut was_connected = false;
         loop {
-            match wifi_subscription.recv().await.unwrap() {
-                WifiEvent::StaDisconnected(_) => {
-                    log::error!("Wifi disconnected! Retrying.");
-                    // Reconnect while ignoring all errors
-                    let _ = wifi.connect(SSID, PASSWORD).await;
+            watchdog.feed();
+
+            if connected && !wifi.is_connected().unwrap_or_default() {
+                log::error!("Wifi disconnected! Retrying.");
+                connected = false;
+                logger::set_connected(false);
+            }
+
+            if !connected {
+                match wifi.connect().await {
+                    Ok(()) => {
+                        if was_connected {
+                            metrics::wifi_reconnected();
+                        }
+                        connected = true;
+                        was_connected = true;
+                        logger::set_connected(true);
+                        if let Err(err) = health_confirmation.confirm() {
+                            log::error!("Failed to confirm firmware: {err}");
+                        }
+                    }
+                    Err(err) => log::error!("Failed connecting to wifi {err}! Retrying."),
                 }
-                _ => (),
             }
+
+            health_confirmation.check_deadline();
+            supervisor.check(connected);
+            timer.after(SUPERVISOR_INTERVAL).await.unwrap();
         }
     });
 
@@ -3,7 +3,7 @@ use esp_idf_svc::mdns::EspMdns;
 
 const MDNS_SERVICE_NAME: &str = "_efm";
 const MDNS_SERVICE_PROTOCOL: &str = "_tcp";
-const MDNS_SERVICE_PORT: u16 = 80;
+const MDNS_SERVICE_PORT: u16 = 443;
 
 pub fn init(hostname: &str) -> Result<EspMdns> {
     log::info!("Initialize mDNS");
@@ -0,0 +1,180 @@
+use esp_idf_svc::sys::{
+    esp, esp_get_free_heap_size, esp_timer_get_time, esp_wifi_sta_get_ap_info, wifi_ap_record_t,
+};
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
+
+static UART_COMMANDS: AtomicU32 = AtomicU32::new(0);
+static UART_ACKS: AtomicU32 = AtomicU32::new(0);
+static UART_NACKS: AtomicU32 = AtomicU32::new(0);
+static UART_TIMEOUTS: AtomicU32 = AtomicU32::new(0);
+static WIFI_RECONNECTS: AtomicU32 = AtomicU32::new(0);
+static OTA_ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+static HTTP_REQUESTS: Mutex<Vec<HttpRequests>> = Mutex::new(Vec::new());
+
+const PREFIX: &str = env!("CARGO_PKG_NAME");
+
+struct HttpRequests {
+    route: &'static str,
+    status: u16,
+    count: u32,
+}
+
+pub enum UartResponse {
+    Ack,
+    Nack,
+    Timeout,
+}
+
+pub fn uart_command_sent() {
+    UART_COMMANDS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn uart_response(response: UartResponse) {
+    let counter = match response {
+        UartResponse::Ack => &UART_ACKS,
+        UartResponse::Nack => &UART_NACKS,
+        UartResponse::Timeout => &UART_TIMEOUTS,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn wifi_reconnected() {
+    WIFI_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ota_attempted() {
+    OTA_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn http_request(route: &'static str, status: u16) {
+    let mut requests = HTTP_REQUESTS.lock().unwrap();
+    match requests
+        .iter_mut()
+        .find(|requests| requests.route == route && requests.status == status)
+    {
+        Some(requests) => requests.count += 1,
+        None => requests.push(HttpRequests {
+            route,
+            status,
+            count: 1,
+        }),
+    }
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut output = String::new();
+
+    write_metric(
+        &mut output,
+        "uart_commands_total",
+        "counter",
+        "Commands sent to the panel over UART.",
+        &[("", UART_COMMANDS.load(Ordering::Relaxed) as i64)],
+    );
+    write_metric(
+        &mut output,
+        "uart_responses_total",
+        "counter",
+        "Panel responses to UART commands by result.",
+        &[
+            ("result=\"ack\"", UART_ACKS.load(Ordering::Relaxed) as i64),
+            ("result=\"nack\"", UART_NACKS.load(Ordering::Relaxed) as i64),
+            (
+                "result=\"timeout\"",
+                UART_TIMEOUTS.load(Ordering::Relaxed) as i64,
+            ),
+        ],
+    );
+
+    let http_requests = HTTP_REQUESTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|requests| {
+            (
+                format!(
+                    "route=\"{}\",status=\"{}\"",
+                    requests.route, requests.status
+                ),
+                requests.count as i64,
+            )
+        })
+        .collect::<Vec<_>>();
+    let http_requests = http_requests
+        .iter()
+        .map(|(labels, count)| (labels.as_str(), *count))
+        .collect::<Vec<_>>();
+    write_metric(
+        &mut output,
+        "http_requests_total",
+        "counter",
+        "HTTP requests by route and status code.",
+        &http_requests,
+    );
+
+    write_metric(
+        &mut output,
+        "wifi_reconnects_total",
+        "counter",
+        "Wifi reconnects after a disconnect.",
+        &[("", WIFI_RECONNECTS.load(Ordering::Relaxed) as i64)],
+    );
+    if let Some(rssi) = rssi() {
+        write_metric(
+            &mut output,
+            "wifi_rssi_dbm",
+            "gauge",
+            "Signal strength of the connected access point.",
+            &[("", rssi as i64)],
+        );
+    }
+    write_metric(
+        &mut output,
+        "free_heap_bytes",
+        "gauge",
+        "Free heap size.",
+        &[("", unsafe { esp_get_free_heap_size() } as i64)],
+    );
+    write_metric(
+        &mut output,
+        "uptime_seconds",
+        "gauge",
+        "Time since boot.",
+        &[("", unsafe { esp_timer_get_time() } / 1_000_000)],
+    );
+    write_metric(
+        &mut output,
+        "ota_attempts_total",
+        "counter",
+        "Firmware update attempts.",
+        &[("", OTA_ATTEMPTS.load(Ordering::Relaxed) as i64)],
+    );
+
+    output
+}
+
+fn write_metric(output: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, i64)]) {
+    let _ = writeln!(output, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(output, "# TYPE {PREFIX}_{name} {kind}");
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(output, "{PREFIX}_{name} {value}");
+        } else {
+            let _ = writeln!(output, "{PREFIX}_{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+fn rssi() -> Option<i8> {
+    let mut access_point = wifi_ap_record_t::default();
+    // Fails if the station is not connected
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut access_point) }).ok()?;
+    Some(access_point.rssi)
+}
@@ -0,0 +1,520 @@
+use crate::{
+    auth::{Scope, TokenInfo},
+    diagnostics::{Diagnostics, HeapDiagnostics, MemoryDiagnostics, TaskDiagnostics},
+    firmware::AppDescription,
+    http_server::{
+        Clock, CreatedToken, ErrorResponse, Status, TokenCreation, TokenRevocation,
+        HOSTNAME_MAX_LENGTH, TEXT_MAX_LENGTH,
+    },
+    logger::{LogLevel, LogLevels},
+    ota::{Progress, SlotInfo, UpdateState},
+    rate_limit::RateLimits,
+};
+use am03127::page_content::{Lagging, Leading, WaitingModeAndSpeed};
+use core::any::type_name;
+use esp_idf_svc::http::Method;
+use serde::{
+    de::{self, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer, Serialize,
+};
+use serde_json::{json, Map, Value};
+
+const OPENAPI_VERSION: &str = "3.0.3";
+const API_TITLE: &str = "LED panel";
+const SCHEMA_PREFIX: &str = "#/components/schemas/";
+
+/// Renders the OpenAPI document of the endpoints mounted below `base_path`.
+/// Endpoints without a description below are still listed so none is missing.
+pub fn document(
+    base_path: &str,
+    endpoints: &[(&'static str, Method)],
+) -> serde_json::Result<Vec<u8>> {
+    let mut paths = Map::new();
+    for (path, method) in endpoints {
+        let operation = describe(path, *method).unwrap_or_else(|| {
+            log::warn!("Endpoint {path} is not described in the OpenAPI document");
+            Operation::new(*path)
+        });
+        let path = path.replace('*', "{id}");
+        let Value::Object(item) = paths.entry(path).or_insert_with(|| json!({})) else {
+            continue;
+        };
+        item.insert(method_name(*method).to_string(), operation.into_value());
+    }
+
+    serde_json::to_vec(&json!({
+        "openapi": OPENAPI_VERSION,
+        "info": {
+            "title": API_TITLE,
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": base_path }],
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+            "schemas": schemas(),
+        },
+    }))
+}
+
+/// Operation of an endpoint, built up by `describe`.
+struct Operation {
+    summary: &'static str,
+    scope: Option<Scope>,
+    parameters: Vec<Value>,
+    request_body: Option<Value>,
+    body_required: bool,
+    status: u16,
+    response: Option<Value>,
+}
+
+impl Operation {
+    fn new(summary: &'static str) -> Self {
+        Self {
+            summary,
+            scope: None,
+            parameters: Vec::new(),
+            request_body: None,
+            body_required: true,
+            status: 200,
+            response: None,
+        }
+    }
+
+    fn scope(mut self, scope: Scope) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    fn parameter(mut self, location: &str, name: &str, description: &str) -> Self {
+        self.parameters.push(json!({
+            "in": location,
+            "name": name,
+            "description": description,
+            "schema": { "type": "string" },
+        }));
+        self
+    }
+
+    fn request(mut self, content_type: &str, schema: Value) -> Self {
+        let content = self.request_body.get_or_insert_with(|| json!({}));
+        content[content_type] = json!({ "schema": schema });
+        self
+    }
+
+    fn optional_body(mut self) -> Self {
+        self.body_required = false;
+        self
+    }
+
+    fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    fn response(mut self, content_type: &str, schema: Value) -> Self {
+        self.response = Some(json!({ content_type: { "schema": schema } }));
+        self
+    }
+
+    fn into_value(self) -> Value {
+        let mut success = json!({ "description": "Success" });
+        if let Some(content) = self.response {
+            success["content"] = content;
+        }
+        let mut responses = Map::new();
+        responses.insert(self.status.to_string(), success);
+        responses.insert(
+            "default".to_string(),
+            json!({
+                "description": "Error",
+                "content": { "application/json": { "schema": reference("ErrorResponse") } },
+            }),
+        );
+
+        let mut operation = json!({
+            "summary": self.summary,
+            "responses": responses,
+        });
+        match self.scope {
+            Some(scope) => {
+                operation["description"] = format!("Requires the `{}` scope.", scope.name()).into();
+                operation["security"] = json!([{ "bearer": [] }]);
+            }
+            None => operation["security"] = json!([]),
+        }
+        if !self.parameters.is_empty() {
+            operation["parameters"] = self.parameters.into();
+        }
+        if let Some(content) = self.request_body {
+            operation["requestBody"] =
+                json!({ "required": self.body_required, "content": content });
+        }
+        operation
+    }
+}
+
+fn describe(path: &str, method: Method) -> Option<Operation> {
+    let operation = match (path, method) {
+        ("/status", Method::Get) => Operation::new("Firmware version, boot slot and reset reason")
+            .response("application/json", reference("Status")),
+        ("/text", Method::Get) => Operation::new("Show text with the default effects")
+            .scope(Scope::Content)
+            .parameter("query", "msg", "URL encoded text"),
+        ("/text", Method::Post) => Operation::new("Show text on the panel")
+            .scope(Scope::Content)
+            .parameter("query", "msg", "URL encoded text, replaces the body")
+            .request("application/json", reference("FormattedText"))
+            .request(
+                "text/plain",
+                json!({ "type": "string", "maxLength": TEXT_MAX_LENGTH }),
+            )
+            .request(
+                "application/x-www-form-urlencoded",
+                object(json!({ "msg": { "type": "string", "maxLength": TEXT_MAX_LENGTH } })),
+            )
+            .optional_body(),
+        ("/clock", Method::Get) => {
+            Operation::new("Show date and time on the panel").scope(Scope::Content)
+        }
+        ("/clock", Method::Post) => Operation::new("Set the real time clock of the panel")
+            .scope(Scope::Config)
+            .request("application/json", reference("Clock")),
+        ("/update", Method::Post) => Operation::new("Upload and boot new firmware")
+            .scope(Scope::Firmware)
+            .parameter(
+                "query",
+                "sha256",
+                "Expected hex encoded SHA-256 of the firmware",
+            )
+            .parameter(
+                "header",
+                "X-Firmware-SHA256",
+                "Expected hex encoded SHA-256 of the firmware",
+            )
+            .parameter(
+                "query",
+                "signature",
+                "Hex encoded Ed25519 signature of the SHA-256",
+            )
+            .parameter(
+                "header",
+                "X-Firmware-Signature",
+                "Hex encoded Ed25519 signature of the SHA-256",
+            )
+            .parameter("query", "force", "`true` to accept older firmware")
+            .parameter(
+                "query",
+                "display",
+                "`false` to not show the progress on the panel",
+            )
+            .parameter(
+                "header",
+                "Content-Encoding",
+                "`gzip` for compressed firmware",
+            )
+            .request(
+                "application/octet-stream",
+                json!({ "type": "string", "format": "binary" }),
+            )
+            .request(
+                "multipart/form-data",
+                json!({
+                    "type": "object",
+                    "properties": { "file": { "type": "string", "format": "binary" } },
+                }),
+            ),
+        ("/update/progress", Method::Get) => Operation::new("Progress of the latest update")
+            .scope(Scope::Read)
+            .response("application/json", reference("Progress")),
+        ("/update/slots", Method::Get) => Operation::new("Firmware in the OTA slots")
+            .scope(Scope::Read)
+            .response("application/json", array(reference("SlotInfo"))),
+        ("/update/slots/switch", Method::Post) => {
+            Operation::new("Boot the firmware of the other slot").scope(Scope::Firmware)
+        }
+        ("/update/check", Method::Post) => Operation::new("Check the update manifest now")
+            .scope(Scope::Firmware)
+            .status(202),
+        ("/metrics", Method::Get) => Operation::new("Prometheus metrics")
+            .scope(Scope::Read)
+            .response("text/plain", json!({ "type": "string" })),
+        ("/diagnostics", Method::Get) => Operation::new("Reset reason and last panic")
+            .scope(Scope::Read)
+            .response("application/json", reference("Diagnostics")),
+        ("/diagnostics/memory", Method::Get) => Operation::new("Heap and task stack usage")
+            .scope(Scope::Read)
+            .response("application/json", reference("MemoryDiagnostics")),
+        ("/log-level", Method::Get) => Operation::new("Log levels changed at runtime")
+            .scope(Scope::Read)
+            .response("application/json", reference("LogLevels")),
+        ("/log-level", Method::Post) => Operation::new("Change log levels")
+            .scope(Scope::Config)
+            .request("application/json", reference("LogLevelUpdate"))
+            .response("application/json", reference("LogLevels")),
+        ("/rate-limit", Method::Get) => Operation::new("Rate limits per client")
+            .scope(Scope::Read)
+            .response("application/json", reference("RateLimits")),
+        ("/rate-limit", Method::Post) => Operation::new("Change and store the rate limits")
+            .scope(Scope::Config)
+            .request("application/json", reference("RateLimits"))
+            .response("application/json", reference("RateLimits")),
+        ("/tls/certificate", Method::Post) => {
+            Operation::new("Store the HTTPS certificate and restart")
+                .scope(Scope::Config)
+                .request(
+                    "application/x-pem-file",
+                    json!({
+                        "type": "string",
+                        "description": "Certificate chain followed by the private key",
+                    }),
+                )
+        }
+        ("/tokens", Method::Get) => Operation::new("API tokens")
+            .scope(Scope::Config)
+            .response("application/json", array(reference("TokenInfo"))),
+        ("/tokens", Method::Post) => Operation::new("Create an API token")
+            .scope(Scope::Config)
+            .request("application/json", reference("TokenCreation"))
+            .response("application/json", reference("CreatedToken")),
+        ("/tokens/revoke", Method::Post) => Operation::new("Revoke an API token")
+            .scope(Scope::Config)
+            .request("application/json", reference("TokenRevocation")),
+        ("/tokens/*", Method::Delete) => Operation::new("Revoke an API token")
+            .scope(Scope::Config)
+            .parameter("path", "id", "Id of the token"),
+        _ => return None,
+    };
+    Some(operation)
+}
+
+fn schemas() -> Value {
+    json!({
+        "ErrorResponse": object_of::<ErrorResponse>(json!({
+            "error": { "type": "string", "description": "Machine readable error code" },
+            "field": { "type": "string", "nullable": true },
+        })),
+        "Status": object_of::<Status>(json!({
+            "hostname": { "type": "string", "maxLength": HOSTNAME_MAX_LENGTH },
+            "last_panic": { "type": "string", "nullable": true },
+        })),
+        "Clock": required(
+            object(json!({
+                "year": { "type": "integer", "minimum": 0, "maximum": 99 },
+                "month": { "type": "integer", "minimum": 1, "maximum": 12 },
+                "day": { "type": "integer", "minimum": 1, "maximum": 31 },
+                "hour": { "type": "integer", "minimum": 0, "maximum": 23 },
+                "minute": { "type": "integer", "minimum": 0, "maximum": 59 },
+                "second": { "type": "integer", "minimum": 0, "maximum": 59 },
+            })),
+            fields::<Clock>(),
+        ),
+        "FormattedText": {
+            "type": "object",
+            "required": ["text"],
+            "additionalProperties": false,
+            "properties": {
+                "text": { "type": "string", "maxLength": TEXT_MAX_LENGTH },
+                "leading": reference("Leading"),
+                "lagging": reference("Lagging"),
+                "waiting_mode_and_speed": reference("WaitingModeAndSpeed"),
+            },
+        },
+        "Leading": enumeration(variants::<Leading>()),
+        "Lagging": enumeration(variants::<Lagging>()),
+        "WaitingModeAndSpeed": enumeration(variants::<WaitingModeAndSpeed>()),
+        "Progress": object_of::<Progress>(json!({
+            "state": enumeration(variants::<UpdateState>()),
+            "expected_size": { "type": "integer", "nullable": true },
+        })),
+        "SlotInfo": object_of::<SlotInfo>(json!({
+            "firmware": {
+                "allOf": [reference("AppDescription")],
+                "nullable": true,
+            },
+        })),
+        "AppDescription": object_of::<AppDescription>(json!({})),
+        "Diagnostics": object_of::<Diagnostics>(json!({
+            "last_panic": { "type": "string", "nullable": true },
+            "restart_reason": { "type": "string", "nullable": true },
+        })),
+        "MemoryDiagnostics": object_of::<MemoryDiagnostics>(json!({
+            "heap": array(object_of::<HeapDiagnostics>(json!({}))),
+            "tasks": array(object_of::<TaskDiagnostics>(json!({}))),
+        })),
+        "LogLevel": enumeration(variants::<LogLevel>()),
+        "LogLevels": object_of::<LogLevels>(json!({
+            "level": {
+                "allOf": [reference("LogLevel")],
+                "nullable": true,
+            },
+            "targets": {
+                "type": "object",
+                "additionalProperties": reference("LogLevel"),
+            },
+        })),
+        "LogLevelUpdate": object(json!({
+            "level": reference("LogLevel"),
+            "targets": {
+                "type": "object",
+                "additionalProperties": reference("LogLevel"),
+            },
+            "clear": {
+                "type": "array",
+                "items": { "type": "string" },
+                "description": "Targets falling back to the global level",
+            },
+            "persist": { "type": "boolean", "default": false },
+        })),
+        "RateLimits": object_of::<RateLimits>(json!({
+            "requests_per_minute": { "type": "integer", "description": "0 disables rate limiting" },
+            "auth_failures": { "type": "integer", "description": "0 disables the lockout" },
+        })),
+        "Scope": enumeration(variants::<Scope>()),
+        "TokenInfo": object_of::<TokenInfo>(json!({
+            "scopes": array(reference("Scope")),
+        })),
+        "TokenCreation": required(
+            object(json!({
+                "scopes": array(reference("Scope")),
+            })),
+            fields::<TokenCreation>(),
+        ),
+        "CreatedToken": object_of::<CreatedToken>(json!({
+            "token": { "type": "string", "description": "Only returned once" },
+            "scopes": array(reference("Scope")),
+        })),
+        "TokenRevocation": required(
+            object(json!({
+                "id": { "type": "string" },
+            })),
+            fields::<TokenRevocation>(),
+        ),
+    })
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("{SCHEMA_PREFIX}{name}") })
+}
+
+fn object(properties: Value) -> Value {
+    json!({ "type": "object", "properties": properties })
+}
+
+/// Object schema with a property for every field `T` serializes, read from its `Serialize`
+/// implementation. Strings, numbers and booleans are typed from the default value, all other
+/// fields have to be described in `properties`. Every field is required since it is always sent.
+fn object_of<T: Serialize + Default>(properties: Value) -> Value {
+    let Value::Object(mut described) = properties else {
+        return object(properties);
+    };
+    let Ok(Value::Object(fields)) = serde_json::to_value(T::default()) else {
+        log::warn!("{} is not serialized as object", type_name::<T>());
+        return object(Value::Object(described));
+    };
+    for name in described.keys().filter(|name| !fields.contains_key(*name)) {
+        log::warn!("{} has no field {name}", type_name::<T>());
+    }
+
+    let required = fields.keys().cloned().collect::<Vec<_>>();
+    let properties = fields
+        .into_iter()
+        .map(|(name, value)| {
+            let schema = described.remove(&name).unwrap_or_else(|| match value {
+                Value::Bool(_) => json!({ "type": "boolean" }),
+                Value::Number(number) if number.is_f64() => json!({ "type": "number" }),
+                Value::Number(_) => json!({ "type": "integer" }),
+                Value::String(_) => json!({ "type": "string" }),
+                _ => {
+                    log::warn!("Field {name} of {} is not described", type_name::<T>());
+                    json!({})
+                }
+            });
+            (name, schema)
+        })
+        .collect::<Map<_, _>>();
+    required(object(Value::Object(properties)), &required)
+}
+
+fn required<S: Serialize>(mut object: Value, fields: &[S]) -> Value {
+    object["required"] = json!(fields);
+    object
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+fn enumeration(variants: &[&str]) -> Value {
+    json!({ "type": "string", "enum": variants })
+}
+
+fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Get => "get",
+        Method::Post => "post",
+        Method::Put => "put",
+        Method::Delete => "delete",
+        Method::Head => "head",
+        Method::Options => "options",
+        Method::Patch => "patch",
+        _ => "x-other",
+    }
+}
+
+/// Serialized names of the variants of an enum, read from its `Deserialize` implementation.
+/// Keeps the document in sync with enums of other crates like `am03127`.
+fn variants<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    names::<T>()
+}
+
+/// Serialized names of the fields of a struct, read from its `Deserialize` implementation.
+fn fields<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    names::<T>()
+}
+
+fn names<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    let mut names = None;
+    // Always fails since no value is produced
+    let _ = T::deserialize(Names(&mut names));
+    names.unwrap_or_default()
+}
+
+/// Deserializer capturing the variant names an enum or the field names a struct asks for.
+struct Names<'a>(&'a mut Option<&'static [&'static str]>);
+
+impl<'de> Deserializer<'de> for Names<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("neither an enum nor a struct"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = Some(variants);
+        Err(de::Error::custom("variant names collected"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = Some(fields);
+        Err(de::Error::custom("field names collected"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map identifier
+        ignored_any
+    }
+}
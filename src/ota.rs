@@ -0,0 +1,515 @@
+use crate::{
+    firmware::{self, AppDescription},
+    hex,
+};
+use anyhow::Result;
+use core::{ffi::CStr, ptr};
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH};
+use esp_idf_svc::{
+    hal::reset::restart,
+    ota::{EspOta, EspOtaUpdate, SlotState},
+    sys::{
+        self, esp_ota_get_boot_partition, esp_ota_get_next_update_partition,
+        esp_ota_get_running_partition, esp_ota_get_state_partition, esp_ota_set_boot_partition,
+        esp_partition_find_first, esp_partition_read,
+        esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_0,
+        esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_1, esp_partition_t,
+        esp_partition_type_t_ESP_PARTITION_TYPE_APP, EspError, ESP_ERR_NOT_FOUND,
+    },
+    timer::EspTimerService,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    sync::{Mutex, MutexGuard, TryLockError},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+pub const PARTITION_SIZE: usize = 0x1f0000;
+pub const DIGEST_LENGTH: usize = 32;
+pub const SIGNATURE_LENGTH: usize = ed25519_dalek::SIGNATURE_LENGTH;
+pub type FirmwareDigest = [u8; DIGEST_LENGTH];
+pub type FirmwareSignature = [u8; SIGNATURE_LENGTH];
+
+/// Ed25519 public key firmware images have to be signed with, given hex encoded.
+/// Required and decoded at build time so signature verification can not be disabled by accident.
+const PUBLIC_KEY: [u8; PUBLIC_KEY_LENGTH] = match hex::decode(env!("FIRMWARE_PUBLIC_KEY")) {
+    Some(public_key) => public_key,
+    None => panic!("FIRMWARE_PUBLIC_KEY must be 64 hex characters"),
+};
+const HEALTH_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+// Held for as long as an update is written to the update slot
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+static PROGRESS: Mutex<Progress> = Mutex::new(Progress {
+    state: UpdateState::Idle,
+    bytes_written: 0,
+    expected_size: None,
+});
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateState {
+    #[default]
+    Idle,
+    Receiving,
+    Verifying,
+    Completed,
+    Failed,
+}
+
+/// Progress of the latest update since boot.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Progress {
+    pub state: UpdateState,
+    pub bytes_written: usize,
+    /// `None` if the size is not known in advance
+    pub expected_size: Option<usize>,
+}
+
+impl Progress {
+    pub fn percent(&self) -> Option<u8> {
+        let expected_size = self.expected_size.filter(|size| *size > 0)?;
+        Some((self.bytes_written.min(expected_size) * 100 / expected_size) as u8)
+    }
+}
+
+pub fn progress() -> Progress {
+    *PROGRESS.lock().unwrap()
+}
+
+fn update_progress(f: impl FnOnce(&mut Progress)) {
+    f(&mut PROGRESS.lock().unwrap());
+}
+
+#[derive(Error, Debug)]
+pub enum OtaError {
+    #[error("Firmware exceeds the partition size of {PARTITION_SIZE} bytes")]
+    TooLarge,
+    #[error("Not an ESP application image")]
+    InvalidImage,
+    #[error("Firmware built for chip {actual:#06x}, expected {expected:#06x}")]
+    ChipMismatch { expected: u16, actual: u16 },
+    #[error("Firmware built for project \"{actual}\", expected \"{expected}\"")]
+    ProjectMismatch { expected: String, actual: String },
+    #[error("Refusing downgrade from {running} to {uploaded}, use force to override")]
+    Downgrade { running: String, uploaded: String },
+    #[error("SHA-256 mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+    #[error("Firmware signature required")]
+    SignatureRequired,
+    #[error("Firmware signature verification failed")]
+    InvalidSignature,
+    #[error("Another firmware update is in progress")]
+    InProgress,
+    #[error("Flashing failed: {0}")]
+    Esp(#[from] EspError),
+}
+
+/// Exclusive right to write the update slot, see [`lock_update`].
+pub struct UpdateLock {
+    _guard: MutexGuard<'static, ()>,
+}
+
+/// Fails with [`OtaError::InProgress`] while an upload or download writes the update slot.
+/// Has to be taken before [`EspOta::new`], which only allows a single instance.
+pub fn lock_update() -> Result<UpdateLock, OtaError> {
+    match UPDATE_LOCK.try_lock() {
+        Ok(guard) => Ok(UpdateLock { _guard: guard }),
+        // A panicking update leaves nothing behind that needs to be cleaned up
+        Err(TryLockError::Poisoned(err)) => Ok(UpdateLock {
+            _guard: err.into_inner(),
+        }),
+        Err(TryLockError::WouldBlock) => Err(OtaError::InProgress),
+    }
+}
+
+/// Writes a firmware image into the update slot while hashing it.
+/// Nothing is flashed before the image header was validated.
+pub struct Updater<'a> {
+    update: EspOtaUpdate<'a>,
+    _lock: UpdateLock,
+    hasher: Sha256,
+    bytes_written: usize,
+    allow_downgrade: bool,
+    // Start of the image until it is long enough to be validated
+    header: Vec<u8>,
+    validated: bool,
+}
+
+impl<'a> Updater<'a> {
+    pub fn new(
+        ota: &'a mut EspOta,
+        lock: UpdateLock,
+        expected_size: Option<usize>,
+    ) -> Result<Self, OtaError> {
+        let running_slot = ota.get_running_slot()?;
+        let update_slot = ota.get_update_slot()?;
+        log::info!(
+            "Current slot: {} - {}",
+            running_slot.label,
+            running_slot
+                .firmware
+                .map(|firmware| firmware.version)
+                .unwrap_or_default()
+        );
+        log::info!("Update slot: {}", update_slot.label);
+
+        let update = ota.initiate_update()?;
+        update_progress(|progress| {
+            *progress = Progress {
+                state: UpdateState::Receiving,
+                bytes_written: 0,
+                expected_size,
+            }
+        });
+
+        Ok(Self {
+            update,
+            _lock: lock,
+            hasher: Sha256::new(),
+            bytes_written: 0,
+            allow_downgrade: false,
+            header: Vec::with_capacity(firmware::HEADER_LENGTH),
+            validated: false,
+        })
+    }
+
+    /// Accepts images older than the running firmware.
+    pub fn allow_downgrade(mut self, allow_downgrade: bool) -> Self {
+        self.allow_downgrade = allow_downgrade;
+        self
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), OtaError> {
+        if self.bytes_written + chunk.len() > PARTITION_SIZE {
+            return Err(OtaError::TooLarge);
+        }
+        self.hasher.update(chunk);
+        if self.validated {
+            self.update.write(chunk)?;
+        } else {
+            self.header.extend_from_slice(chunk);
+            if self.header.len() >= firmware::HEADER_LENGTH {
+                self.validate()?;
+                self.update.write(&self.header)?;
+                self.header = Vec::new();
+                self.validated = true;
+            }
+        }
+        self.bytes_written += chunk.len();
+        update_progress(|progress| progress.bytes_written = self.bytes_written);
+        Ok(())
+    }
+
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
+    fn validate(&self) -> Result<(), OtaError> {
+        let uploaded = AppDescription::parse(&self.header).ok_or(OtaError::InvalidImage)?;
+        let running = AppDescription::running();
+        log::info!(
+            "Uploaded firmware: {} {} built {} {}",
+            uploaded.project_name,
+            uploaded.version,
+            uploaded.build_date,
+            uploaded.build_time
+        );
+
+        if uploaded.chip_id != running.chip_id {
+            return Err(OtaError::ChipMismatch {
+                expected: running.chip_id,
+                actual: uploaded.chip_id,
+            });
+        }
+        if uploaded.project_name != running.project_name {
+            return Err(OtaError::ProjectMismatch {
+                expected: running.project_name,
+                actual: uploaded.project_name,
+            });
+        }
+        if !self.allow_downgrade
+            && parse_version(&uploaded.version) < parse_version(&running.version)
+        {
+            return Err(OtaError::Downgrade {
+                running: running.version,
+                uploaded: uploaded.version,
+            });
+        }
+        Ok(())
+    }
+
+    /// Verifies the image and marks the update slot bootable.
+    /// The update is aborted if the digest or signature do not match.
+    pub fn complete(
+        self,
+        expected_digest: Option<&FirmwareDigest>,
+        signature: Option<&FirmwareSignature>,
+    ) -> Result<(), OtaError> {
+        update_progress(|progress| progress.state = UpdateState::Verifying);
+        let result = self.verify_and_complete(expected_digest, signature);
+        update_progress(|progress| {
+            progress.state = match result {
+                Ok(()) => UpdateState::Completed,
+                Err(_) => UpdateState::Failed,
+            }
+        });
+        result
+    }
+
+    fn verify_and_complete(
+        self,
+        expected_digest: Option<&FirmwareDigest>,
+        signature: Option<&FirmwareSignature>,
+    ) -> Result<(), OtaError> {
+        if !self.validated {
+            // The image was shorter than its header
+            self.update.abort()?;
+            return Err(OtaError::InvalidImage);
+        }
+
+        let digest: FirmwareDigest = self.hasher.finalize().into();
+        log::info!("Firmware SHA-256: {}", hex::encode(&digest));
+
+        if let Some(expected_digest) = expected_digest {
+            if digest != *expected_digest {
+                self.update.abort()?;
+                return Err(OtaError::DigestMismatch {
+                    expected: hex::encode(expected_digest),
+                    actual: hex::encode(&digest),
+                });
+            }
+        }
+
+        // Verify before completing since that marks the new slot bootable
+        match signature {
+            Some(signature) => {
+                if let Err(err) = verify_signature(&digest, signature) {
+                    log::error!("{err}");
+                    self.update.abort()?;
+                    return Err(OtaError::InvalidSignature);
+                }
+                log::info!("Firmware signature verified");
+            }
+            None => {
+                self.update.abort()?;
+                return Err(OtaError::SignatureRequired);
+            }
+        }
+
+        self.update.complete()?;
+        log::info!("Update finished");
+        Ok(())
+    }
+
+    pub fn abort(self) -> Result<(), OtaError> {
+        update_progress(|progress| progress.state = UpdateState::Failed);
+        self.update.abort()?;
+        Ok(())
+    }
+}
+
+pub fn schedule_reboot(delay: Duration) -> Result<(), EspError> {
+    let reboot_timer = EspTimerService::new()?;
+    let reboot_timer = reboot_timer.timer(move || {
+        log::info!("Rebooting");
+        restart();
+    })?;
+    log::info!("Schedule reboot in {} seconds...", delay.as_secs());
+    reboot_timer.after(delay)?;
+    std::mem::forget(reboot_timer);
+    Ok(())
+}
+
+/// Verifies the detached Ed25519 signature over the SHA-256 digest of the firmware image.
+pub fn verify_signature(digest: &FirmwareDigest, signature: &FirmwareSignature) -> Result<()> {
+    let verifying_key = VerifyingKey::from_bytes(&PUBLIC_KEY)?;
+    let signature = Signature::from_bytes(signature);
+    verifying_key.verify_strict(digest, &signature)?;
+    Ok(())
+}
+
+/// Checks that the firmware public key is a valid curve point, which the build can not check.
+/// Without a valid key the device could never be updated over the air again.
+pub fn check_public_key() -> Result<()> {
+    VerifyingKey::from_bytes(&PUBLIC_KEY)?;
+    Ok(())
+}
+
+/// Numeric components of a `major.minor.patch` version, suffixes like `-rc1` are ignored.
+pub fn parse_version(version: &str) -> Vec<u32> {
+    version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|component| component.parse().unwrap_or_default())
+        .collect()
+}
+
+/// One of the two OTA slots as seen from the partition table.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SlotInfo {
+    pub label: String,
+    pub state: &'static str,
+    pub running: bool,
+    /// Slot booted after the next restart
+    pub boot: bool,
+    /// `None` if the slot holds no valid image
+    pub firmware: Option<AppDescription>,
+}
+
+pub fn slots() -> Result<Vec<SlotInfo>, EspError> {
+    let running = unsafe { esp_ota_get_running_partition() };
+    let boot = unsafe { esp_ota_get_boot_partition() };
+
+    [
+        esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_0,
+        esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_1,
+    ]
+    .into_iter()
+    .filter_map(|subtype| {
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_APP,
+                subtype,
+                ptr::null(),
+            )
+        };
+        (!partition.is_null()).then_some(partition)
+    })
+    .map(|partition| {
+        Ok(SlotInfo {
+            label: unsafe { CStr::from_ptr((*partition).label.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+            state: slot_state_name(&partition_state(partition)?),
+            running: ptr::eq(partition, running),
+            boot: ptr::eq(partition, boot),
+            firmware: read_app_description(partition)?,
+        })
+    })
+    .collect()
+}
+
+/// Makes the slot which is not running the boot slot. Returns its label.
+/// With rollback enabled the firmware in it has to confirm its health again after booting.
+pub fn switch_boot_slot() -> Result<String, OtaError> {
+    let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+    if partition.is_null() {
+        return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>().into());
+    }
+    if read_app_description(partition)?.is_none() {
+        return Err(OtaError::InvalidImage);
+    }
+
+    // Verifies the image before it is marked bootable
+    EspError::convert(unsafe { esp_ota_set_boot_partition(partition) })?;
+    let label = unsafe { CStr::from_ptr((*partition).label.as_ptr()) }
+        .to_string_lossy()
+        .into_owned();
+    log::warn!("Boot slot switched to {label}");
+    Ok(label)
+}
+
+fn partition_state(partition: *const esp_partition_t) -> Result<SlotState, EspError> {
+    let mut state = 0;
+    let result = unsafe { esp_ota_get_state_partition(partition, &mut state) };
+    // Slots which were never written have no state
+    if result == ESP_ERR_NOT_FOUND {
+        return Ok(SlotState::Unknown);
+    }
+    EspError::convert(result)?;
+
+    #[allow(non_upper_case_globals)]
+    let state = match state {
+        sys::esp_ota_img_states_t_ESP_OTA_IMG_NEW
+        | sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY => SlotState::Unverified,
+        sys::esp_ota_img_states_t_ESP_OTA_IMG_VALID => SlotState::Valid,
+        sys::esp_ota_img_states_t_ESP_OTA_IMG_INVALID
+        | sys::esp_ota_img_states_t_ESP_OTA_IMG_ABORTED => SlotState::Invalid,
+        _ => SlotState::Unknown,
+    };
+    Ok(state)
+}
+
+fn read_app_description(
+    partition: *const esp_partition_t,
+) -> Result<Option<AppDescription>, EspError> {
+    let mut header = [0u8; firmware::HEADER_LENGTH];
+    EspError::convert(unsafe {
+        esp_partition_read(partition, 0, header.as_mut_ptr().cast(), header.len())
+    })?;
+    Ok(AppDescription::parse(&header))
+}
+
+pub fn slot_state_name(state: &SlotState) -> &'static str {
+    match state {
+        SlotState::Factory => "factory",
+        SlotState::Valid => "valid",
+        SlotState::Invalid => "invalid",
+        SlotState::Unverified => "pending_verify",
+        SlotState::Unknown => "unknown",
+    }
+}
+
+/// Keeps a freshly updated firmware pending until it proved healthy.
+/// If it is not confirmed in time the previous firmware is booted again.
+/// Needs `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`.
+pub struct HealthConfirmation {
+    pending: bool,
+    deadline: Instant,
+}
+
+impl HealthConfirmation {
+    pub fn new() -> Result<Self> {
+        let running_slot = EspOta::new()?.get_running_slot()?;
+        let pending = matches!(running_slot.state, SlotState::Unverified);
+        if pending {
+            log::warn!(
+                "Firmware in slot {} is pending verification",
+                running_slot.label
+            );
+        }
+
+        Ok(Self {
+            pending,
+            deadline: Instant::now() + HEALTH_CONFIRMATION_TIMEOUT,
+        })
+    }
+
+    pub fn confirm(&mut self) -> Result<()> {
+        if !self.pending {
+            return Ok(());
+        }
+        EspOta::new()?.mark_running_slot_valid()?;
+        self.pending = false;
+        log::info!("Firmware confirmed healthy");
+        Ok(())
+    }
+
+    /// Rolls back if the firmware was not confirmed before the deadline.
+    pub fn check_deadline(&self) {
+        if self.pending && Instant::now() >= self.deadline {
+            log::error!("Firmware not confirmed healthy in time");
+            self.roll_back();
+        }
+    }
+
+    /// Marks a pending firmware invalid and reboots into the previous one. Does nothing otherwise.
+    pub fn roll_back(&self) {
+        if !self.pending {
+            return;
+        }
+        log::error!("Rolling back to previous firmware");
+        match EspOta::new() {
+            Ok(mut ota) => {
+                let err = ota.mark_running_slot_invalid_and_reboot();
+                log::error!("Failed to roll back: {err}");
+            }
+            Err(err) => log::error!("Failed to roll back: {err}"),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const NVS_NAMESPACE: &str = "rate_limit";
+const NVS_KEY_LIMITS: &str = "limits";
+const NVS_LIMITS_MAX_SIZE: usize = 256;
+// Clients tracked at once, the least recently seen one is forgotten first
+const CLIENTS_MAX_COUNT: usize = 16;
+const DEFAULT_LIMITS: RateLimits = RateLimits {
+    requests_per_minute: 60,
+    burst: 10,
+    auth_failures: 5,
+    lockout_seconds: 300,
+};
+
+static STATE: Mutex<State> = Mutex::new(State {
+    limits: DEFAULT_LIMITS,
+    clients: Vec::new(),
+});
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Sustained requests per minute and client, 0 disables rate limiting
+    pub requests_per_minute: u32,
+    /// Requests a client may send at once before being limited
+    pub burst: u32,
+    /// Failed authentications before a client is locked out, 0 disables the lockout
+    pub auth_failures: u32,
+    pub lockout_seconds: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        DEFAULT_LIMITS
+    }
+}
+
+struct State {
+    limits: RateLimits,
+    clients: Vec<Client>,
+}
+
+struct Client {
+    ip: IpAddr,
+    tokens: f32,
+    last_seen: Instant,
+    auth_failures: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Restores the limits stored in NVS, the defaults apply otherwise.
+pub fn init(partition: EspDefaultNvsPartition) -> Result<()> {
+    let nvs = EspNvs::<NvsDefault>::new(partition, NVS_NAMESPACE, true)?;
+    let mut buffer = [0u8; NVS_LIMITS_MAX_SIZE];
+    if let Some(limits) = nvs.get_str(NVS_KEY_LIMITS, &mut buffer)? {
+        STATE.lock().unwrap().limits = serde_json::from_str(limits)?;
+    }
+    log::info!("Rate limits: {:?}", limits());
+    Ok(())
+}
+
+pub fn limits() -> RateLimits {
+    STATE.lock().unwrap().limits
+}
+
+/// Applies and stores the limits.
+pub fn set_limits(partition: EspDefaultNvsPartition, limits: RateLimits) -> Result<()> {
+    let mut nvs = EspNvs::<NvsDefault>::new(partition, NVS_NAMESPACE, true)?;
+    nvs.set_str(NVS_KEY_LIMITS, &serde_json::to_string(&limits)?)?;
+    STATE.lock().unwrap().limits = limits;
+    log::info!("Rate limits set to {limits:?}");
+    Ok(())
+}
+
+/// Counts a request of the client. Returns how long it has to wait if it is limited or locked out.
+pub fn check(ip: IpAddr) -> Result<(), Duration> {
+    let now = Instant::now();
+    let mut state = STATE.lock().unwrap();
+    let limits = state.limits;
+    let client = state.client(ip, now);
+
+    if let Some(locked_until) = client.locked_until {
+        if now < locked_until {
+            return Err(locked_until - now);
+        }
+        client.locked_until = None;
+        client.auth_failures = 0;
+    }
+
+    if limits.requests_per_minute == 0 {
+        return Ok(());
+    }
+    // Refill the bucket for the time since the last request
+    let rate = limits.requests_per_minute as f32 / 60.0;
+    let capacity = limits.burst.max(1) as f32;
+    let elapsed = now.duration_since(client.last_seen).as_secs_f32();
+    client.tokens = (client.tokens + elapsed * rate).min(capacity);
+    client.last_seen = now;
+
+    if client.tokens < 1.0 {
+        return Err(Duration::from_secs_f32((1.0 - client.tokens) / rate));
+    }
+    client.tokens -= 1.0;
+    Ok(())
+}
+
+/// Locks the client out once it failed to authenticate too often.
+pub fn auth_failed(ip: IpAddr) {
+    let now = Instant::now();
+    let mut state = STATE.lock().unwrap();
+    let limits = state.limits;
+    let client = state.client(ip, now);
+    client.auth_failures += 1;
+    if limits.auth_failures > 0 && client.auth_failures >= limits.auth_failures {
+        log::warn!(
+            "Locking out {ip} after {} failed authentications",
+            client.auth_failures
+        );
+        client.locked_until = Some(now + Duration::from_secs(limits.lockout_seconds.into()));
+    }
+}
+
+pub fn auth_succeeded(ip: IpAddr) {
+    let now = Instant::now();
+    STATE.lock().unwrap().client(ip, now).auth_failures = 0;
+}
+
+impl State {
+    fn client(&mut self, ip: IpAddr, now: Instant) -> &mut Client {
+        if let Some(index) = self.clients.iter().position(|client| client.ip == ip) {
+            return &mut self.clients[index];
+        }
+
+        let client = Client {
+            ip,
+            tokens: self.limits.burst.max(1) as f32,
+            last_seen: now,
+            auth_failures: 0,
+            locked_until: None,
+        };
+        if self.clients.len() < CLIENTS_MAX_COUNT {
+            self.clients.push(client);
+            return self.clients.last_mut().unwrap();
+        }
+
+        // Forget locked out clients last so they can not escape by flooding from other addresses
+        let index = self
+            .clients
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, client)| {
+                (
+                    client.locked_until.is_some_and(|until| until > now),
+                    client.last_seen,
+                )
+            })
+            .map(|(index, _)| index)
+            .unwrap_or_default();
+        self.clients[index] = client;
+        &mut self.clients[index]
+    }
+}
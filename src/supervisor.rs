@@ -0,0 +1,62 @@
+use crate::diagnostics;
+use esp_idf_svc::{hal::reset::restart, nvs::EspDefaultNvsPartition};
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+const WIFI_LOSS_RESTART_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const PANEL_FAILURES_RESTART_THRESHOLD: u32 = 10;
+
+static PANEL_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+pub fn panel_write_succeeded() {
+    PANEL_FAILURES.store(0, Ordering::Relaxed);
+}
+
+pub fn panel_write_failed() {
+    PANEL_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Restarts the chip if it stays unhealthy for too long and records the reason in the diagnostics.
+pub struct Supervisor {
+    nvs_partition: EspDefaultNvsPartition,
+    wifi_lost_since: Option<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(nvs_partition: EspDefaultNvsPartition) -> Self {
+        Self {
+            nvs_partition,
+            wifi_lost_since: None,
+        }
+    }
+
+    /// Must be called periodically from the main loop.
+    pub fn check(&mut self, wifi_connected: bool) {
+        if wifi_connected {
+            self.wifi_lost_since = None;
+        } else {
+            let wifi_lost_since = *self.wifi_lost_since.get_or_insert_with(Instant::now);
+            if wifi_lost_since.elapsed() >= WIFI_LOSS_RESTART_TIMEOUT {
+                self.restart(&format!(
+                    "Wifi lost for more than {} seconds",
+                    WIFI_LOSS_RESTART_TIMEOUT.as_secs()
+                ));
+            }
+        }
+
+        let panel_failures = PANEL_FAILURES.load(Ordering::Relaxed);
+        if panel_failures >= PANEL_FAILURES_RESTART_THRESHOLD {
+            self.restart(&format!("{panel_failures} consecutive panel failures"));
+        }
+    }
+
+    fn restart(&self, reason: &str) -> ! {
+        log::error!("Restarting: {reason}");
+        if let Err(err) = diagnostics::store_restart_reason(self.nvs_partition.clone(), reason) {
+            log::error!("Failed to store restart reason: {err}");
+        }
+        restart();
+    }
+}
@@ -0,0 +1,167 @@
+use heapless::{Deque, String};
+use log::Level;
+use std::{
+    fmt::Write,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+const DEFAULT_PORT: u16 = 514;
+// local0
+const DEFAULT_FACILITY: u8 = 16;
+const MAX_FACILITY: u8 = 23;
+const VERSION: u8 = 1;
+const NIL_VALUE: &str = "-";
+const APP_NAME: &str = env!("CARGO_PKG_NAME");
+const BUFFER_CAPACITY: usize = 32;
+const TARGET_MAX_LENGTH: usize = 32;
+const MESSAGE_MAX_LENGTH: usize = 192;
+const DATAGRAM_MAX_LENGTH: usize = MESSAGE_MAX_LENGTH + 128;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub host: &'static str,
+    pub port: u16,
+    pub facility: u8,
+}
+
+impl Config {
+    /// Reads the syslog configuration from the `SYSLOG_HOST`, `SYSLOG_PORT` and `SYSLOG_FACILITY`
+    /// build environment variables. Returns `None` if no host is configured.
+    pub fn from_env() -> Option<Self> {
+        let host = option_env!("SYSLOG_HOST").filter(|host| !host.is_empty())?;
+        let port = option_env!("SYSLOG_PORT")
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(DEFAULT_PORT);
+        let facility = option_env!("SYSLOG_FACILITY")
+            .and_then(|facility| facility.parse().ok())
+            .filter(|facility| *facility <= MAX_FACILITY)
+            .unwrap_or(DEFAULT_FACILITY);
+
+        Some(Self {
+            host,
+            port,
+            facility,
+        })
+    }
+}
+
+struct Entry {
+    level: Level,
+    target: String<TARGET_MAX_LENGTH>,
+    message: String<MESSAGE_MAX_LENGTH>,
+}
+
+/// UDP sink sending log records as RFC 5424 messages.
+/// Records are buffered while the network is down and flushed once it is up again.
+pub struct Syslog {
+    config: Config,
+    hostname: String<30>,
+    socket: Option<(UdpSocket, SocketAddr)>,
+    buffer: Deque<Entry, BUFFER_CAPACITY>,
+}
+
+impl Syslog {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            hostname: String::new(),
+            socket: None,
+            buffer: Deque::new(),
+        }
+    }
+
+    pub fn set_hostname(&mut self, hostname: &str) {
+        self.hostname = String::try_from(hostname).unwrap_or_default();
+    }
+
+    pub fn connect(&mut self) {
+        // Do not log in here since the logger is locked while connecting.
+        self.socket = (self.config.host, self.config.port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .and_then(|address| {
+                UdpSocket::bind("0.0.0.0:0")
+                    .ok()
+                    .map(|socket| (socket, address))
+            });
+        self.flush();
+    }
+
+    pub fn disconnect(&mut self) {
+        self.socket = None;
+    }
+
+    pub fn log(&mut self, level: Level, target: &str, message: &str) {
+        let mut entry = Entry {
+            level,
+            target: String::new(),
+            message: String::new(),
+        };
+        push_truncated(&mut entry.target, target);
+        push_truncated(&mut entry.message, message);
+
+        if self.buffer.is_full() {
+            // Drop the oldest record
+            self.buffer.pop_front();
+        }
+        let _ = self.buffer.push_back(entry);
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        let Some((socket, address)) = &self.socket else {
+            return;
+        };
+
+        while let Some(entry) = self.buffer.front() {
+            let datagram = self.format(entry);
+            if socket.send_to(datagram.as_bytes(), address).is_err() {
+                // Keep the record and retry with the next one logged
+                return;
+            }
+            self.buffer.pop_front();
+        }
+    }
+
+    fn format(&self, entry: &Entry) -> String<DATAGRAM_MAX_LENGTH> {
+        let priority = self.config.facility * 8 + severity(entry.level);
+        let hostname = if self.hostname.is_empty() {
+            NIL_VALUE
+        } else {
+            &self.hostname
+        };
+        let message_id = if entry.target.is_empty() {
+            NIL_VALUE
+        } else {
+            &entry.target
+        };
+
+        // <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+        // There is no wall clock, so the timestamp is left to the collector.
+        let mut datagram = String::new();
+        let _ = write!(
+            datagram,
+            "<{priority}>{VERSION} {NIL_VALUE} {hostname} {APP_NAME} {NIL_VALUE} {message_id} {NIL_VALUE} {}",
+            entry.message
+        );
+        datagram
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn push_truncated<const N: usize>(buffer: &mut String<N>, value: &str) {
+    for character in value.chars() {
+        if buffer.push(character).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use anyhow::{bail, Result};
+use core::{
+    ffi::{c_int, c_uchar, c_void, CStr},
+    mem::MaybeUninit,
+    ptr,
+};
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    sys::{self, esp_fill_random},
+};
+use std::ffi::CString;
+use thiserror::Error;
+
+const NVS_NAMESPACE: &str = "tls";
+const NVS_KEY_CERTIFICATE: &str = "certificate";
+const NVS_KEY_PRIVATE_KEY: &str = "private_key";
+const PEM_BUFFER_SIZE: usize = 2048;
+const SERIAL_NUMBER_LENGTH: usize = 16;
+// The panel has no reliable clock, so the certificate is valid for as long as possible
+const VALID_FROM: &CStr = c"20240101000000";
+const VALID_UNTIL: &CStr = c"20991231235959";
+const PEM_BEGIN: &str = "-----BEGIN ";
+const PEM_END: &str = "-----END ";
+const PEM_DASHES: &str = "-----";
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("No certificate found")]
+    MissingCertificate,
+    #[error("No private key found")]
+    MissingPrivateKey,
+    #[error("Invalid certificate")]
+    InvalidCertificate,
+    #[error("Invalid private key")]
+    InvalidPrivateKey,
+    #[error("Private key does not match the certificate")]
+    KeyMismatch,
+    #[error("Storing certificate failed: {0}")]
+    Storage(#[from] esp_idf_svc::sys::EspError),
+}
+
+/// NUL terminated PEM certificate chain and private key of the HTTPS server.
+pub struct Credentials {
+    pub certificate: &'static [u8],
+    pub private_key: &'static [u8],
+}
+
+/// Loads the certificate and key from NVS. Generates and stores a self-signed one if none is provisioned.
+pub fn init(partition: EspDefaultNvsPartition, hostname: &str) -> Result<Credentials> {
+    let nvs = EspNvs::<NvsDefault>::new(partition.clone(), NVS_NAMESPACE, true)?;
+    let certificate = read_blob(&nvs, NVS_KEY_CERTIFICATE)?;
+    let private_key = read_blob(&nvs, NVS_KEY_PRIVATE_KEY)?;
+    let (certificate, private_key) = match (certificate, private_key) {
+        (Some(certificate), Some(private_key)) => (certificate, private_key),
+        _ => {
+            log::warn!("No TLS certificate found, generating a self-signed one");
+            // Wi-Fi is not started yet, so the entropy source has to be enabled explicitly
+            unsafe { sys::bootloader_random_enable() };
+            let generated = generate_self_signed(hostname);
+            unsafe { sys::bootloader_random_disable() };
+            let (certificate, private_key) = generated?;
+            store_pem(partition, &certificate, &private_key)?;
+            (certificate, private_key)
+        }
+    };
+
+    // The server keeps referencing them for as long as it runs
+    Ok(Credentials {
+        certificate: Vec::leak(certificate),
+        private_key: Vec::leak(private_key),
+    })
+}
+
+/// Validates and stores a certificate chain and its private key given as PEM.
+/// Takes effect after the next restart.
+pub fn store(partition: EspDefaultNvsPartition, pem: &str) -> Result<(), TlsError> {
+    let (certificate, private_key) = split_pem(pem);
+    if certificate.is_empty() {
+        return Err(TlsError::MissingCertificate);
+    }
+    let private_key = private_key.ok_or(TlsError::MissingPrivateKey)?;
+
+    let certificate = nul_terminated(&certificate);
+    let private_key = nul_terminated(private_key);
+    validate(&certificate, &private_key)?;
+    store_pem(partition, &certificate, &private_key)?;
+    log::info!("Stored new TLS certificate");
+    Ok(())
+}
+
+fn store_pem(
+    partition: EspDefaultNvsPartition,
+    certificate: &[u8],
+    private_key: &[u8],
+) -> Result<(), TlsError> {
+    let mut nvs = EspNvs::<NvsDefault>::new(partition, NVS_NAMESPACE, true)?;
+    nvs.set_blob(NVS_KEY_CERTIFICATE, certificate)?;
+    nvs.set_blob(NVS_KEY_PRIVATE_KEY, private_key)?;
+    Ok(())
+}
+
+fn read_blob(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<Vec<u8>>> {
+    let Some(length) = nvs.blob_len(key)? else {
+        return Ok(None);
+    };
+    let mut buffer = vec![0; length];
+    Ok(nvs.get_blob(key, &mut buffer)?.map(<[u8]>::to_vec))
+}
+
+/// Splits PEM into the certificate chain and the private key, other blocks are dropped.
+fn split_pem(pem: &str) -> (String, Option<&str>) {
+    let mut certificate = String::new();
+    let mut private_key = None;
+    let mut rest = pem;
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        let block = &rest[start..];
+        let label_start = PEM_BEGIN.len();
+        let Some(label_length) = block[label_start..].find(PEM_DASHES) else {
+            break;
+        };
+        let label = &block[label_start..label_start + label_length];
+        let end_marker = format!("{PEM_END}{label}{PEM_DASHES}");
+        let Some(end) = block.find(&end_marker) else {
+            break;
+        };
+        let block = &block[..end + end_marker.len()];
+
+        if label == "CERTIFICATE" {
+            certificate.push_str(block);
+            certificate.push('\n');
+        } else if label.ends_with("PRIVATE KEY") {
+            private_key = Some(block);
+        }
+        rest = &rest[start + block.len()..];
+    }
+    (certificate, private_key)
+}
+
+fn nul_terminated(pem: &str) -> Vec<u8> {
+    let mut bytes = pem.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+fn validate(certificate: &[u8], private_key: &[u8]) -> Result<(), TlsError> {
+    unsafe {
+        let mut crt = MaybeUninit::<sys::mbedtls_x509_crt>::uninit();
+        let mut key = MaybeUninit::<sys::mbedtls_pk_context>::uninit();
+        sys::mbedtls_x509_crt_init(crt.as_mut_ptr());
+        sys::mbedtls_pk_init(key.as_mut_ptr());
+
+        let result = if sys::mbedtls_x509_crt_parse(
+            crt.as_mut_ptr(),
+            certificate.as_ptr(),
+            certificate.len(),
+        ) != 0
+        {
+            Err(TlsError::InvalidCertificate)
+        } else if sys::mbedtls_pk_parse_key(
+            key.as_mut_ptr(),
+            private_key.as_ptr(),
+            private_key.len(),
+            ptr::null(),
+            0,
+            Some(random),
+            ptr::null_mut(),
+        ) != 0
+        {
+            Err(TlsError::InvalidPrivateKey)
+        } else if sys::mbedtls_pk_check_pair(
+            &(*crt.as_ptr()).pk,
+            key.as_ptr(),
+            Some(random),
+            ptr::null_mut(),
+        ) != 0
+        {
+            Err(TlsError::KeyMismatch)
+        } else {
+            Ok(())
+        };
+
+        sys::mbedtls_pk_free(key.as_mut_ptr());
+        sys::mbedtls_x509_crt_free(crt.as_mut_ptr());
+        result
+    }
+}
+
+/// Generates a P-256 key and a certificate for `<hostname>.local` signed by itself.
+/// Needs a true random source, i.e. running Wi-Fi or the bootloader entropy source enabled.
+fn generate_self_signed(hostname: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let subject = CString::new(format!("CN={hostname}.local"))?;
+    let mut serial_number = [0u8; SERIAL_NUMBER_LENGTH];
+    unsafe { esp_fill_random(serial_number.as_mut_ptr().cast(), serial_number.len()) };
+    // Serial numbers have to be positive
+    serial_number[0] &= 0x7f;
+
+    let mut certificate = vec![0u8; PEM_BUFFER_SIZE];
+    let mut private_key = vec![0u8; PEM_BUFFER_SIZE];
+    unsafe {
+        let mut key = MaybeUninit::<sys::mbedtls_pk_context>::uninit();
+        let mut crt = MaybeUninit::<sys::mbedtls_x509write_cert>::uninit();
+        sys::mbedtls_pk_init(key.as_mut_ptr());
+        sys::mbedtls_x509write_crt_init(crt.as_mut_ptr());
+        let key = key.as_mut_ptr();
+        let crt = crt.as_mut_ptr();
+
+        let result = (|| {
+            check(sys::mbedtls_pk_setup(
+                key,
+                sys::mbedtls_pk_info_from_type(sys::mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+            ))?;
+            // Equivalent of the inline `mbedtls_pk_ec`
+            check(sys::mbedtls_ecp_gen_key(
+                sys::mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+                (*key).private_pk_ctx.cast(),
+                Some(random),
+                ptr::null_mut(),
+            ))?;
+
+            sys::mbedtls_x509write_crt_set_version(crt, sys::MBEDTLS_X509_CRT_VERSION_3 as c_int);
+            sys::mbedtls_x509write_crt_set_md_alg(crt, sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+            sys::mbedtls_x509write_crt_set_subject_key(crt, key);
+            sys::mbedtls_x509write_crt_set_issuer_key(crt, key);
+            check(sys::mbedtls_x509write_crt_set_subject_name(
+                crt,
+                subject.as_ptr(),
+            ))?;
+            check(sys::mbedtls_x509write_crt_set_issuer_name(
+                crt,
+                subject.as_ptr(),
+            ))?;
+            check(sys::mbedtls_x509write_crt_set_serial_raw(
+                crt,
+                serial_number.as_mut_ptr(),
+                serial_number.len(),
+            ))?;
+            check(sys::mbedtls_x509write_crt_set_validity(
+                crt,
+                VALID_FROM.as_ptr(),
+                VALID_UNTIL.as_ptr(),
+            ))?;
+            check(sys::mbedtls_x509write_crt_set_basic_constraints(crt, 0, -1))?;
+
+            check(sys::mbedtls_x509write_crt_pem(
+                crt,
+                certificate.as_mut_ptr(),
+                certificate.len(),
+                Some(random),
+                ptr::null_mut(),
+            ))?;
+            check(sys::mbedtls_pk_write_key_pem(
+                key,
+                private_key.as_mut_ptr(),
+                private_key.len(),
+            ))
+        })();
+
+        sys::mbedtls_x509write_crt_free(crt);
+        sys::mbedtls_pk_free(key);
+        result?;
+    }
+
+    // Keep the PEM including its terminating NUL
+    for pem in [&mut certificate, &mut private_key] {
+        let length = pem
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(pem.len() - 1);
+        pem.truncate(length + 1);
+    }
+    log::info!("Generated self-signed TLS certificate for {hostname}.local");
+    Ok((certificate, private_key))
+}
+
+fn check(result: c_int) -> Result<()> {
+    if result != 0 {
+        bail!("mbedTLS failed with -{:#06x}", -result);
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn random(_context: *mut c_void, output: *mut c_uchar, length: usize) -> c_int {
+    esp_fill_random(output.cast(), length);
+    0
+}
@@ -1,4 +1,8 @@
-use am03127::{self};
+use crate::{
+    metrics::{self, UartResponse},
+    supervisor,
+};
+use am03127::{self, page_content::PageContent};
 use anyhow::{bail, Context, Result};
 use esp_idf_svc::hal::{
     gpio::{AnyIOPin, InputPin, OutputPin},
@@ -43,17 +47,31 @@ impl Uart {
         Ok(())
     }
 
+    /// Shows plain text with the default effects.
+    pub fn display(&self, text: &str) -> Result<()> {
+        let command = PageContent::default().message(text).command();
+        self.write(&command)
+    }
+
     pub fn write(&self, command: &str) -> Result<()> {
         let mut buffer = [0; READ_BUFFER_SIZE];
         let _ = self.uart.write(command.as_bytes())?;
-        let _ = self.uart.read(&mut buffer, READ_TIMEOUT)?;
+        metrics::uart_command_sent();
+        let bytes_read = self.uart.read(&mut buffer, READ_TIMEOUT)?;
         let result = String::from_utf8_lossy(&buffer);
 
         log::info!("Receiving: {}", &result);
         if result.starts_with("ACK") {
+            metrics::uart_response(UartResponse::Ack);
+            supervisor::panel_write_succeeded();
             return Ok(());
         } else if result.starts_with("NACK") {
+            metrics::uart_response(UartResponse::Nack);
+            supervisor::panel_write_failed();
             bail!("NACK");
+        } else if bytes_read == 0 {
+            metrics::uart_response(UartResponse::Timeout);
+            supervisor::panel_write_failed();
         }
 
         Ok(())
@@ -0,0 +1,204 @@
+use crate::{
+    hex,
+    ota::{self, Updater},
+    watchdog::Watchdog,
+};
+use anyhow::{bail, Context, Result};
+use embedded_svc::http::{client::Client, Headers, Status};
+use esp_idf_svc::{
+    http::client::{Configuration, EspHttpConnection},
+    io::Read,
+    ota::EspOta,
+    sys::esp_crt_bundle_attach,
+};
+use serde::Deserialize;
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+/// URL of the JSON manifest describing the latest firmware.
+/// The update client is disabled if no URL is compiled in.
+const MANIFEST_URL: Option<&str> = option_env!("OTA_MANIFEST_URL");
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MANIFEST_MAX_SIZE: usize = 1024;
+// Well below the task watchdog timeout since the watchdog is only fed between reads
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const HTTP_STATUS_OK: u16 = 200;
+const DOWNLOAD_CHUNK_SIZE: usize = 1024 * 4;
+const REBOOT_DELAY: Duration = Duration::from_secs(5);
+// TLS needs a lot of stack
+const STACK_SIZE: usize = 1024 * 12;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub url: String,
+    /// Hex encoded SHA-256 digest of the firmware image
+    pub sha256: String,
+    /// Hex encoded Ed25519 signature over the SHA-256 digest
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Oldest running version allowed to update to this one directly
+    #[serde(default)]
+    pub min_version: Option<String>,
+}
+
+/// Triggers an update check outside of the regular interval.
+#[derive(Clone)]
+pub struct UpdateTrigger(Sender<()>);
+
+impl UpdateTrigger {
+    /// Returns `false` if the update client is not running anymore.
+    pub fn trigger(&self) -> bool {
+        self.0.send(()).is_ok()
+    }
+}
+
+/// Starts a task checking the manifest periodically and flashing newer firmware.
+/// Returns `None` if no manifest URL is compiled in.
+pub fn start() -> Result<Option<UpdateTrigger>> {
+    let Some(manifest_url) = MANIFEST_URL else {
+        log::info!("No OTA manifest URL configured, update client disabled");
+        return Ok(None);
+    };
+
+    log::info!("Initialize update client for {manifest_url}");
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("update_client".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || loop {
+            match receiver.recv_timeout(CHECK_INTERVAL) {
+                Ok(()) => log::info!("Update check triggered"),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if let Err(err) = check(manifest_url) {
+                log::error!("Update check failed: {err:#}");
+            }
+        })?;
+
+    Ok(Some(UpdateTrigger(sender)))
+}
+
+fn check(manifest_url: &str) -> Result<()> {
+    log::info!("Checking for updates");
+    let manifest = fetch_manifest(manifest_url)?;
+
+    let running_version = EspOta::new()?
+        .get_running_slot()?
+        .firmware
+        .map(|firmware| firmware.version)
+        .unwrap_or_default();
+    let current = ota::parse_version(&running_version);
+
+    if ota::parse_version(&manifest.version) <= current {
+        log::info!("Firmware {running_version} is up to date");
+        return Ok(());
+    }
+    if let Some(min_version) = &manifest.min_version {
+        if current < ota::parse_version(min_version) {
+            bail!(
+                "Firmware {} requires at least {min_version}, running {running_version}",
+                manifest.version
+            );
+        }
+    }
+
+    let expected_digest = hex::decode::<{ ota::DIGEST_LENGTH }>(&manifest.sha256)
+        .context("Invalid SHA-256 digest in manifest")?;
+    // Checked before downloading since unsigned firmware is rejected anyway
+    let signature = manifest
+        .signature
+        .as_deref()
+        .context("Manifest lacks the firmware signature")?;
+    let signature = hex::decode::<{ ota::SIGNATURE_LENGTH }>(signature)
+        .context("Invalid signature in manifest")?;
+
+    log::info!(
+        "Updating from {running_version} to {} using {}",
+        manifest.version,
+        manifest.url
+    );
+    download(&manifest.url, &expected_digest, Some(&signature))?;
+    ota::schedule_reboot(REBOOT_DELAY)?;
+    Ok(())
+}
+
+fn fetch_manifest(url: &str) -> Result<Manifest> {
+    let mut client = create_client()?;
+    let mut response = client.get(url)?.submit()?;
+    if response.status() != HTTP_STATUS_OK {
+        bail!("Fetching manifest failed with status {}", response.status());
+    }
+
+    let mut body = Vec::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        let bytes_read = response.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        if body.len() + bytes_read > MANIFEST_MAX_SIZE {
+            bail!("Manifest exceeds {MANIFEST_MAX_SIZE} bytes");
+        }
+        body.extend_from_slice(&buffer[..bytes_read]);
+    }
+
+    Ok(serde_json::from_slice(&body).context("Invalid manifest")?)
+}
+
+fn download(
+    url: &str,
+    expected_digest: &ota::FirmwareDigest,
+    signature: Option<&ota::FirmwareSignature>,
+) -> Result<()> {
+    let watchdog = Watchdog::subscribe()?;
+    let mut client = create_client()?;
+    let mut response = client.get(url)?.submit()?;
+    if response.status() != HTTP_STATUS_OK {
+        bail!(
+            "Downloading firmware failed with status {}",
+            response.status()
+        );
+    }
+
+    let lock = ota::lock_update()?;
+    let mut ota = EspOta::new()?;
+    let expected_size = response.content_len().map(|size| size as usize);
+    let mut updater = Updater::new(&mut ota, lock, expected_size)?;
+    let mut buffer = vec![0u8; DOWNLOAD_CHUNK_SIZE];
+    loop {
+        watchdog.feed();
+        let bytes_read = match response.read(&mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => {
+                updater.abort()?;
+                return Err(err.into());
+            }
+        };
+        if bytes_read == 0 {
+            break;
+        }
+        if let Err(err) = updater.write(&buffer[..bytes_read]) {
+            updater.abort()?;
+            return Err(err.into());
+        }
+    }
+
+    log::info!("Downloaded {} bytes", updater.bytes_written());
+    updater.complete(Some(expected_digest), signature)?;
+    Ok(())
+}
+
+fn create_client() -> Result<Client<EspHttpConnection>> {
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(HTTP_TIMEOUT),
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    Ok(Client::wrap(connection))
+}
@@ -0,0 +1,223 @@
+use crate::{
+    http_body::BodyError,
+    ota::{self, FirmwareDigest, FirmwareSignature, OtaError, Updater},
+    uart::Uart,
+    watchdog::Watchdog,
+};
+use esp_idf_svc::{io::Read, sys::HTTPD_SOCK_ERR_TIMEOUT};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+pub const CHUNK_SIZE: usize = 1024 * 8;
+// Abort if the client stops sending for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const TOTAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const DISPLAY_PROGRESS_STEP: u8 = 10;
+
+#[derive(Error, Debug)]
+pub enum UploadError {
+    #[error("Upload timed out after {received} bytes")]
+    Timeout { received: usize },
+    #[error("Upload ended after {received} of {expected} bytes")]
+    Incomplete { received: usize, expected: usize },
+    #[error("Reading upload failed: {0}")]
+    Read(BodyError),
+    #[error(transparent)]
+    Ota(#[from] OtaError),
+}
+
+/// Firmware upload with optional verification.
+pub struct Upload {
+    /// `None` if the upload only ends with the body, e.g. for chunked or multipart bodies
+    pub expected_size: Option<usize>,
+    pub expected_digest: Option<FirmwareDigest>,
+    pub signature: Option<FirmwareSignature>,
+    /// Panel showing the progress, if any
+    pub panel: Option<Arc<Mutex<Uart>>>,
+}
+
+enum State<'a> {
+    Receiving {
+        updater: Updater<'a>,
+        last_progress: Instant,
+    },
+    Verifying {
+        updater: Updater<'a>,
+    },
+    Completed,
+    Failed {
+        // Still open updates have to be aborted
+        updater: Option<Updater<'a>>,
+        error: UploadError,
+    },
+}
+
+impl Upload {
+    /// Streams the firmware from the reader into the updater.
+    /// The new slot is only marked bootable if the state machine reaches `Completed`.
+    pub fn run<R>(
+        &self,
+        reader: &mut R,
+        updater: Updater<'_>,
+        watchdog: &Watchdog,
+    ) -> Result<(), UploadError>
+    where
+        R: Read<Error = BodyError> + ?Sized,
+    {
+        let started = Instant::now();
+        let mut buffer = [0; CHUNK_SIZE];
+        let mut state = State::Receiving {
+            updater,
+            last_progress: started,
+        };
+        let mut displayed_percent = None;
+        let mut panel_failed = false;
+
+        match self.expected_size {
+            Some(expected_size) => log::info!("Start uploading. Expected {expected_size} bytes"),
+            None => log::info!("Start uploading of unknown size"),
+        }
+        loop {
+            state = match state {
+                State::Receiving {
+                    updater,
+                    last_progress,
+                } => {
+                    watchdog.feed();
+                    let state = self.receive(reader, &mut buffer, updater, last_progress, started);
+                    self.display_progress(&mut displayed_percent, &mut panel_failed);
+                    state
+                }
+                State::Verifying { updater } => {
+                    match updater.complete(self.expected_digest.as_ref(), self.signature.as_ref()) {
+                        Ok(()) => State::Completed,
+                        // Completing aborts the update itself on failure
+                        Err(err) => State::Failed {
+                            updater: None,
+                            error: err.into(),
+                        },
+                    }
+                }
+                State::Completed => return Ok(()),
+                State::Failed { updater, error } => {
+                    log::error!("Upload failed: {error}");
+                    if let Some(updater) = updater {
+                        if let Err(err) = updater.abort() {
+                            log::error!("Failed to abort update: {err}");
+                        }
+                    }
+                    return Err(error);
+                }
+            };
+        }
+    }
+
+    /// Shows the text on the panel if there is one. Failures are only logged
+    /// since the panel must not interfere with the update. Returns `false` if the panel failed.
+    pub fn display(&self, text: &str) -> bool {
+        let Some(panel) = &self.panel else {
+            return true;
+        };
+        let result = panel
+            .lock()
+            .map_err(|err| anyhow::anyhow!("Failed to lock UART: {:?}", err))
+            .and_then(|uart| uart.display(text));
+        if let Err(err) = &result {
+            log::warn!("Failed to display \"{text}\": {err}");
+        }
+        result.is_ok()
+    }
+
+    fn display_progress(&self, displayed_percent: &mut Option<u8>, panel_failed: &mut bool) {
+        // Every failed write counts towards the supervisor restarting the chip, even mid update
+        if *panel_failed {
+            return;
+        }
+        let Some(percent) = ota::progress().percent() else {
+            return;
+        };
+        let percent = percent / DISPLAY_PROGRESS_STEP * DISPLAY_PROGRESS_STEP;
+        if *displayed_percent != Some(percent) {
+            *displayed_percent = Some(percent);
+            *panel_failed = !self.display(&format!("UPDATING {percent}%"));
+        }
+    }
+
+    fn receive<'a, R>(
+        &self,
+        reader: &mut R,
+        buffer: &mut [u8],
+        mut updater: Updater<'a>,
+        last_progress: Instant,
+        started: Instant,
+    ) -> State<'a>
+    where
+        R: Read<Error = BodyError> + ?Sized,
+    {
+        let received = updater.bytes_written();
+        let timeout = UploadError::Timeout { received };
+        if started.elapsed() >= TOTAL_TIMEOUT {
+            return State::Failed {
+                updater: Some(updater),
+                error: timeout,
+            };
+        }
+
+        // Never read beyond the announced size
+        let remaining = self
+            .expected_size
+            .map_or(CHUNK_SIZE, |expected_size| expected_size - received);
+        let chunk = &mut buffer[..remaining.min(CHUNK_SIZE)];
+        match reader.read(chunk) {
+            Ok(0) => match self.expected_size {
+                Some(expected) => State::Failed {
+                    updater: Some(updater),
+                    error: UploadError::Incomplete { received, expected },
+                },
+                // Without an announced size the end of the body ends the upload
+                None => State::Verifying { updater },
+            },
+            Ok(bytes_read) => {
+                if let Err(err) = updater.write(&chunk[..bytes_read]) {
+                    return State::Failed {
+                        updater: Some(updater),
+                        error: err.into(),
+                    };
+                }
+                log::info!("Read {} bytes from firmware", updater.bytes_written());
+
+                if self
+                    .expected_size
+                    .is_some_and(|expected_size| updater.bytes_written() >= expected_size)
+                {
+                    State::Verifying { updater }
+                } else {
+                    State::Receiving {
+                        updater,
+                        last_progress: Instant::now(),
+                    }
+                }
+            }
+            Err(BodyError::Io(err)) if err.0.code() == HTTPD_SOCK_ERR_TIMEOUT => {
+                if last_progress.elapsed() < IDLE_TIMEOUT {
+                    State::Receiving {
+                        updater,
+                        last_progress,
+                    }
+                } else {
+                    State::Failed {
+                        updater: Some(updater),
+                        error: timeout,
+                    }
+                }
+            }
+            Err(err) => State::Failed {
+                updater: Some(updater),
+                error: UploadError::Read(err),
+            },
+        }
+    }
+}
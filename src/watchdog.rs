@@ -0,0 +1,29 @@
+use core::{marker::PhantomData, ptr};
+use esp_idf_svc::sys::{esp, esp_task_wdt_add, esp_task_wdt_delete, esp_task_wdt_reset, EspError};
+
+/// Subscription of the current task to the ESP-IDF task watchdog.
+/// The task has to be fed within `CONFIG_ESP_TASK_WDT_TIMEOUT_S`, otherwise the chip panics and restarts.
+/// The subscription ends when dropped.
+pub struct Watchdog {
+    // The subscription belongs to the task which created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Watchdog {
+    pub fn subscribe() -> Result<Self, EspError> {
+        esp!(unsafe { esp_task_wdt_add(ptr::null_mut()) })?;
+        Ok(Self {
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn feed(&self) {
+        unsafe { esp_task_wdt_reset() };
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        unsafe { esp_task_wdt_delete(ptr::null_mut()) };
+    }
+}
@@ -18,13 +18,13 @@ pub struct Wifi<'a> {
 }
 
 impl<'a> Wifi<'a> {
-    pub fn new(event_loop: EspSystemEventLoop, modem: Modem) -> Result<Self> {
+    pub fn new(
+        event_loop: EspSystemEventLoop,
+        modem: Modem,
+        nvs_partition: EspDefaultNvsPartition,
+    ) -> Result<Self> {
         log::info!("Initialize wifi");
-        let driver = WifiDriver::new(
-            modem,
-            event_loop.clone(),
-            Some(EspDefaultNvsPartition::take()?),
-        )?;
+        let driver = WifiDriver::new(modem, event_loop.clone(), Some(nvs_partition))?;
         let mac_address = driver.get_mac(WifiDeviceId::Sta)?;
         let hostname = base36::encode(mac_address);
         log::info!("Set wifi hostname to {hostname}");
@@ -55,8 +55,8 @@ impl<'a> Wifi<'a> {
         network_configuration
     }
 
-    pub async fn connect(&mut self, ssid: &str, password: &str) -> Result<()> {
-        log::info!("Connect to wifi {}", ssid);
+    pub async fn start(&mut self, ssid: &str, password: &str) -> Result<()> {
+        log::info!("Configure wifi {}", ssid);
         let configuration = wifi::Configuration::Client(wifi::ClientConfiguration {
             ssid: ssid.try_into().unwrap(),
             auth_method: AuthMethod::WPA2Personal,
@@ -68,15 +68,21 @@ impl<'a> Wifi<'a> {
         self.wifi.set_configuration(&configuration)?;
         log::info!("Start");
         self.wifi.start().await?;
+        Ok(())
+    }
 
-        while let Err(err) = self.wifi.connect().await {
-            log::error!("Failed connecting to wifi {err}! Retrying.");
-        }
-
+    /// Makes a single connection attempt, retrying is up to the caller.
+    pub async fn connect(&mut self) -> Result<()> {
+        log::info!("Connect to wifi");
+        self.wifi.connect().await?;
         self.wifi.wait_netif_up().await?;
         Ok(())
     }
 
+    pub fn is_connected(&self) -> Result<bool> {
+        Ok(self.wifi.is_connected()?)
+    }
+
     pub fn get_hostname(&self) -> Result<String<30>> {
         Ok(self.wifi.wifi().sta_netif().get_hostname()?)
     }